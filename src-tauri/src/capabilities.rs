@@ -0,0 +1,336 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commands::StructureTypes,
+    error::Error,
+    tlv::{TlvReader, TlvStructure},
+    VersionInfo,
+};
+
+/// The protocol version this client speaks, the same as `API_VERSION` in the frontend. Firmwares
+/// report the range of versions they accept in VersionStatus.
+pub const PROTOCOL_VERSION: u16 = 4;
+
+/// The filter types this client can encode.
+const FILTER_TYPES: [u8; 10] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+
+/// The sample rate responses are evaluated at when there is no device to ask.
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
+/// The rates a USB audio device is commonly played at, assumed for firmwares which don't report
+/// the rates they support.
+const DEFAULT_SAMPLE_RATES: [u32; 3] = [44100, 48000, 96000];
+
+/// The parameter ranges filters are checked against, as reported in FirmwareCapabilities. The
+/// defaults, for firmwares which don't report them, are this client's own, chosen to catch typos
+/// rather than taken from any firmware.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FilterLimits {
+    pub min_q: f32,
+    pub max_q: f32,
+    /// The largest boost or cut, in dB.
+    pub max_gain_db: f32,
+}
+
+impl Default for FilterLimits {
+    fn default() -> Self {
+        Self {
+            min_q: 0.01,
+            max_q: 100.0,
+            max_gain_db: 30.0,
+        }
+    }
+}
+
+/// Set in the FirmwareCapabilities flags when the firmware can run separate filters on each channel.
+const PER_CHANNEL_EQ: u8 = 1 << 0;
+/// Set in the FirmwareCapabilities flags when the firmware accepts Pcm3060Configuration.
+const CODEC: u8 = 1 << 1;
+
+/// The optional FirmwareCapabilities structure nested in VersionStatus. This is an extension of our
+/// own, the reference firmware doesn't send it and gets the defaults, only the simulator does. The
+/// value is the maximum number of filters, a flags byte, a reserved byte, a bitmap of the supported
+/// filter types, the minimum Q, maximum Q and largest gain in dB as floats, then the supported
+/// sample rates.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FirmwareCapabilities {
+    pub max_filters: u16,
+    pub per_channel_eq: bool,
+    pub codec: bool,
+    pub filter_types: Vec<u8>,
+    pub filter_limits: FilterLimits,
+    pub sample_rates: Vec<u32>,
+}
+
+impl TlvStructure for FirmwareCapabilities {
+    const TYPE: u16 = StructureTypes::FirmwareCapabilities as u16;
+
+    fn encode_value(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.max_filters.to_le_bytes());
+        let mut flags = 0;
+        if self.per_channel_eq {
+            flags |= PER_CHANNEL_EQ;
+        }
+        if self.codec {
+            flags |= CODEC;
+        }
+        buf.push(flags);
+        buf.push(0); // reserved byte
+        let bitmap = self
+            .filter_types
+            .iter()
+            .filter(|&&t| t < 32)
+            .fold(0u32, |bitmap, t| bitmap | 1 << t);
+        buf.extend_from_slice(&bitmap.to_le_bytes());
+        let limits = &self.filter_limits;
+        for limit in [limits.min_q, limits.max_q, limits.max_gain_db] {
+            buf.extend_from_slice(&limit.to_le_bytes());
+        }
+        for rate in &self.sample_rates {
+            buf.extend_from_slice(&rate.to_le_bytes());
+        }
+    }
+
+    fn decode_value(cur: &mut TlvReader) -> Result<Self, Error> {
+        let max_filters = cur.read_u16()?;
+        let flags = cur.read_u8()?;
+        cur.skip(1)?; // reserved byte
+        let bitmap = cur.read_u32()?;
+        let filter_types = (0..32).filter(|t| bitmap & 1 << t != 0).collect();
+        let filter_limits = FilterLimits {
+            min_q: cur.read_f32()?,
+            max_q: cur.read_f32()?,
+            max_gain_db: cur.read_f32()?,
+        };
+        let mut sample_rates = Vec::new();
+        while !cur.is_empty() {
+            sample_rates.push(cur.read_u32()?);
+        }
+        Ok(Self {
+            max_filters,
+            per_channel_eq: flags & PER_CHANNEL_EQ != 0,
+            codec: flags & CODEC != 0,
+            filter_types,
+            filter_limits,
+            sample_rates,
+        })
+    }
+}
+
+/// What the connected firmware can do, agreed when the device is opened. `write_config` checks
+/// configurations against this before encoding them.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    /// The configuration structures the firmware accepts.
+    pub structures: Vec<u16>,
+    /// The filter type discriminants the firmware can run.
+    pub filter_types: Vec<u8>,
+    /// The most filters the firmware can run, if it says.
+    pub max_filters: Option<u16>,
+    pub sample_rates: Vec<u32>,
+    /// Whether the firmware can run separate filters on each channel. Configurations don't carry
+    /// per-channel filters yet, this is for the frontend to decide what to offer.
+    pub per_channel_eq: bool,
+    pub filter_limits: FilterLimits,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            structures: vec![
+                StructureTypes::PreProcessingConfiguration as u16,
+                StructureTypes::FilterConfiguration as u16,
+                StructureTypes::Pcm3060Configuration as u16,
+            ],
+            filter_types: FILTER_TYPES.to_vec(),
+            max_filters: None,
+            sample_rates: DEFAULT_SAMPLE_RATES.to_vec(),
+            per_channel_eq: false,
+            filter_limits: FilterLimits::default(),
+        }
+    }
+}
+
+impl Capabilities {
+    /// Narrows what this client can encode to what the firmware reported.
+    fn with_firmware(mut self, firmware: &Option<FirmwareCapabilities>) -> Self {
+        if let Some(firmware) = firmware {
+            self.filter_types
+                .retain(|t| firmware.filter_types.contains(t));
+            self.max_filters = Some(firmware.max_filters);
+            if !firmware.sample_rates.is_empty() {
+                self.sample_rates = firmware.sample_rates.clone();
+            }
+            self.per_channel_eq = firmware.per_channel_eq;
+            self.filter_limits = firmware.filter_limits.clone();
+            if !firmware.codec {
+                self.structures
+                    .retain(|&s| s != StructureTypes::Pcm3060Configuration as u16);
+            }
+        }
+        self
+    }
+
+    /// Checks the firmware accepts our protocol version. Firmwares which don't are refused, we
+    /// have no other layout to send them and they would misread this one.
+    pub fn negotiate(version: &VersionInfo) -> Result<Self, Error> {
+        if !(version.minimum_supported_version..=version.current_version)
+            .contains(&PROTOCOL_VERSION)
+        {
+            return Err(Error::VersionMismatch {
+                client_version: PROTOCOL_VERSION,
+                minimum_supported_version: version.minimum_supported_version,
+                current_version: version.current_version,
+            });
+        }
+        Ok(Self::default().with_firmware(&version.capabilities))
+    }
+
+    pub fn supports(&self, structure: StructureTypes) -> bool {
+        self.structures.contains(&(structure as u16))
+    }
+
+    /// The highest frequency a filter can be centred on, half the fastest sample rate. Filters
+    /// which only break at the slower rates are warned about rather than rejected.
+    pub fn nyquist(&self) -> f32 {
+        let sample_rate = self
+            .sample_rates
+            .iter()
+            .max()
+            .copied()
+            .unwrap_or(DEFAULT_SAMPLE_RATE);
+        sample_rate as f32 / 2.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::{CustomIIRFilter, Filters, PeakingFilter, Validate};
+
+    fn version(minimum_supported_version: u16, current_version: u16) -> VersionInfo {
+        VersionInfo {
+            current_version,
+            minimum_supported_version,
+            git_hash: String::new(),
+            pico_sdk_version: String::new(),
+            capabilities: None,
+        }
+    }
+
+    #[test]
+    fn negotiates_versions() {
+        assert_eq!(
+            Capabilities::negotiate(&version(1, PROTOCOL_VERSION)).unwrap(),
+            Capabilities::default()
+        );
+        assert_eq!(
+            Capabilities::negotiate(&version(PROTOCOL_VERSION, PROTOCOL_VERSION + 2)).unwrap(),
+            Capabilities::default()
+        );
+
+        // Older firmwares, newer firmwares which dropped our version, and nonsense ranges are
+        // refused
+        for (min, current) in [
+            (1, PROTOCOL_VERSION - 1),
+            (PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 1),
+            (0, 0),
+            (PROTOCOL_VERSION + 1, PROTOCOL_VERSION - 1),
+        ] {
+            assert!(matches!(
+                Capabilities::negotiate(&version(min, current)),
+                Err(Error::VersionMismatch { .. })
+            ));
+        }
+    }
+
+    fn firmware() -> FirmwareCapabilities {
+        FirmwareCapabilities {
+            max_filters: 2,
+            per_channel_eq: true,
+            codec: false,
+            filter_types: vec![0, 1, 6, 9],
+            filter_limits: FilterLimits {
+                min_q: 0.1,
+                max_q: 20.0,
+                max_gain_db: 12.0,
+            },
+            sample_rates: vec![44100, 48000, 96000],
+        }
+    }
+
+    #[test]
+    fn firmware_capabilities_round_trip() {
+        let mut buf = Vec::new();
+        firmware().encode(&mut buf);
+        assert_eq!(buf.len(), 4 + 8 + 12 + 12);
+        let decoded = FirmwareCapabilities::decode(&mut TlvReader::new(&buf)).unwrap();
+        assert_eq!(decoded, firmware());
+    }
+
+    #[test]
+    fn firmware_capabilities_narrow_the_version() {
+        let mut info = version(1, PROTOCOL_VERSION);
+        info.capabilities = Some(firmware());
+        let capabilities = Capabilities::negotiate(&info).unwrap();
+        assert_eq!(capabilities.filter_types, vec![0, 1, 6, 9]);
+        assert_eq!(capabilities.max_filters, Some(2));
+        assert_eq!(capabilities.sample_rates, vec![44100, 48000, 96000]);
+        assert!(capabilities.per_channel_eq);
+        assert_eq!(capabilities.filter_limits.max_gain_db, 12.0);
+        assert!(!capabilities.supports(StructureTypes::Pcm3060Configuration));
+
+        // Filter types this client can't encode stay unsupported
+        let mut firmware = firmware();
+        firmware.filter_types.push(20);
+        assert!(!Capabilities::default()
+            .with_firmware(&Some(firmware))
+            .filter_types
+            .contains(&20));
+    }
+
+    #[test]
+    fn rejects_unsupported_filters() {
+        let mut filters = Filters::default();
+        filters.add(PeakingFilter::new(1000.0, 1.0, 1.0).unwrap().into(), false);
+        filters.add(
+            CustomIIRFilter::new(1.0, 0.0, 0.0, 1.0, 0.0, 0.0).into(),
+            true,
+        );
+
+        let mut capabilities = Capabilities::default();
+        assert!(filters.validate(&capabilities).is_ok());
+        capabilities.filter_types.retain(|&t| t != 9);
+        assert_eq!(
+            filters.validate(&capabilities).unwrap_err().to_string(),
+            "Filter 2: This firmware doesn't support CustomIIR filters."
+        );
+        assert!(matches!(
+            filters.validate(&capabilities),
+            Err(Error::InvalidFilter {
+                index: Some(1),
+                field: Some("filter_type"),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_too_many_filters() {
+        let capabilities = Capabilities::default().with_firmware(&Some(firmware()));
+        let mut filters = Filters::default();
+        for f0 in [100.0, 200.0, 300.0] {
+            filters.add(PeakingFilter::new(f0, 1.0, 1.0).unwrap().into(), true);
+        }
+        assert_eq!(
+            filters.validate(&capabilities).unwrap_err().to_string(),
+            "This firmware supports up to 2 filters, but 3 are enabled."
+        );
+
+        // Disabled filters don't count
+        let mut filters = Filters::default();
+        for (f0, enabled) in [(100.0, true), (200.0, false), (300.0, true)] {
+            filters.add(PeakingFilter::new(f0, 1.0, 1.0).unwrap().into(), enabled);
+        }
+        assert!(filters.validate(&capabilities).is_ok());
+    }
+}
@@ -1,7 +1,10 @@
 use std::io::Write;
 
 use crate::{
+    capabilities::Capabilities,
+    error::Error,
     filters::{Filters, Validate},
+    tlv::{encode_tlv, TlvStructure},
     Codec, Preprocessing,
 };
 
@@ -15,8 +18,8 @@ pub enum StructureTypes {
     // Commands/Responses, these are container TLVs. The Value will be a set of TLV structures.
     Ok = 0,      // Standard response when a command was successful
     Nok,         // Standard error response
-    FlashHeader, // A special container for the config stored in flash. Hopefully there is some useful
-    // metadata in here to allow us to migrate an old config to a new version.
+    FlashHeader, // Reserved for the firmware's own header on the config stored in flash. It is never
+    // sent to us, GetStoredConfiguration answers with the same TLVs as GetActiveConfiguration.
     GetVersion, // Returns the current config version, and the minimum supported version so clients
     // can decide if they can talk to us or not.
     SetConfiguration, // Updates the active configuration with the supplied TLVs
@@ -24,6 +27,8 @@ pub enum StructureTypes {
     GetStoredConfiguration, // Retrieves the current stored configuration TLVs from Flash
     SaveConfiguration, // Writes the active configuration to Flash
     FactoryReset,     // Invalidates the flash memory
+    Chunk, // One segment of a frame too large for a single transfer. Carries a sequence number and
+    // the total length of the frame being reassembled, only used when negotiated via GetVersion.
 
     // Configuration structures, these are returned in the body of a command/response
     PreProcessingConfiguration = 0x200,
@@ -33,6 +38,38 @@ pub enum StructureTypes {
     // Status structures, these are returned in the body of a command/response but they are
     // not persisted as part of the configuration
     VersionStatus = 0x400,
+    TransferCapabilities, // Optionally returned by GetVersion when the firmware supports chunked transfers
+    ErrorStatus, // Simulator only, the reference firmware sends an empty Nok. Says why the request
+    // was rejected and which TLV was at fault
+    FirmwareCapabilities, // Our extension, optionally nested in VersionStatus, describes what the
+                          // firmware can run. Only the simulator sends it
+}
+
+/// The reason codes carried in an ErrorStatus structure. These aren't defined by the reference
+/// firmware, only the simulator sends them.
+#[repr(u16)]
+#[derive(Debug, Clone, Copy)]
+#[allow(dead_code)]
+pub enum NokCode {
+    Unspecified = 0,
+    UnknownCommand,
+    InvalidLength,
+    UnsupportedStructure,
+    InvalidValue,
+    LimitExceeded,
+}
+
+impl NokCode {
+    pub fn describe(code: u16) -> &'static str {
+        match code {
+            x if x == NokCode::UnknownCommand as u16 => "unknown command",
+            x if x == NokCode::InvalidLength as u16 => "invalid length",
+            x if x == NokCode::UnsupportedStructure as u16 => "unsupported structure",
+            x if x == NokCode::InvalidValue as u16 => "invalid value",
+            x if x == NokCode::LimitExceeded as u16 => "exceeds the limits of the device",
+            _ => "no reason given",
+        }
+    }
 }
 
 pub struct GetVersion();
@@ -60,28 +97,26 @@ impl<'a> SetPreprocessingConfiguration<'a> {
 
 impl Command for SetPreprocessingConfiguration<'_> {
     fn write_as_binary(&self, mut buf: impl Write) {
-        let payload = self.0.to_payload();
-        let _ = buf.write(&(StructureTypes::PreProcessingConfiguration as u16).to_le_bytes());
-        let _ = buf.write(&((4 + payload.len()) as u16).to_le_bytes());
-        let _ = buf.write(&payload);
+        let mut tlv = Vec::new();
+        self.0.encode(&mut tlv);
+        let _ = buf.write(&tlv);
     }
 }
 
 pub struct SetFilterConfiguration<'a>(&'a Filters);
 
 impl<'a> SetFilterConfiguration<'a> {
-    pub fn new(filters: &'a Filters) -> Result<Self, String> {
-        filters.validate()?;
+    pub fn new(filters: &'a Filters, capabilities: &Capabilities) -> Result<Self, Error> {
+        filters.validate(capabilities)?;
         Ok(Self(filters))
     }
 }
 
 impl Command for SetFilterConfiguration<'_> {
     fn write_as_binary(&self, mut buf: impl Write) {
-        let payload = self.0.to_payload();
-        let _ = buf.write(&(StructureTypes::FilterConfiguration as u16).to_le_bytes());
-        let _ = buf.write(&((4 + payload.len()) as u16).to_le_bytes());
-        let _ = buf.write(&payload);
+        let mut tlv = Vec::new();
+        self.0.encode(&mut tlv);
+        let _ = buf.write(&tlv);
     }
 }
 
@@ -95,17 +130,16 @@ impl<'a> SetPcm3060Configuration<'a> {
 
 impl Command for SetPcm3060Configuration<'_> {
     fn write_as_binary(&self, mut buf: impl Write) {
-        let payload = self.0.to_payload();
-        let _ = buf.write(&(StructureTypes::Pcm3060Configuration as u16).to_le_bytes());
-        let _ = buf.write(&((4 + payload.len()) as u16).to_le_bytes());
-        let _ = buf.write(&payload);
+        let mut tlv = Vec::new();
+        self.0.encode(&mut tlv);
+        let _ = buf.write(&tlv);
     }
 }
 
 pub struct SetConfiguration<'a, 'b, 'c> {
     preprocessing: SetPreprocessingConfiguration<'a>,
     filter: SetFilterConfiguration<'b>,
-    codec: SetPcm3060Configuration<'c>,
+    codec: Option<SetPcm3060Configuration<'c>>,
 }
 
 impl<'a, 'b, 'c> SetConfiguration<'a, 'b, 'c> {
@@ -117,24 +151,62 @@ impl<'a, 'b, 'c> SetConfiguration<'a, 'b, 'c> {
         Self {
             preprocessing,
             filter,
-            codec,
+            codec: Some(codec),
+        }
+    }
+
+    /// Leaves out the codec settings, for boards which have no codec to configure.
+    pub fn without_codec(self) -> Self {
+        Self {
+            codec: None,
+            ..self
         }
     }
 }
 
 impl Command for SetConfiguration<'_, '_, '_> {
     fn write_as_binary(&self, mut buf: impl Write) {
-        let _ = buf.write(&(StructureTypes::SetConfiguration as u16).to_le_bytes());
-        let _ = buf.write(
-            &((16
-                + self.filter.0.to_payload().len()
-                + self.preprocessing.0.to_payload().len()
-                + self.codec.0.to_payload().len()) as u16)
-                .to_le_bytes(),
-        );
-        let _ = &self.preprocessing.write_as_binary(&mut buf);
-        let _ = &self.filter.write_as_binary(&mut buf);
-        let _ = &self.codec.write_as_binary(&mut buf);
+        let mut value = Vec::new();
+        self.preprocessing.0.encode(&mut value);
+        self.filter.0.encode(&mut value);
+        if let Some(codec) = &self.codec {
+            codec.0.encode(&mut value);
+        }
+
+        let mut tlv = Vec::new();
+        encode_tlv(&mut tlv, StructureTypes::SetConfiguration as u16, |tlv| {
+            tlv.extend(value)
+        });
+        let _ = buf.write(&tlv);
+    }
+}
+
+pub struct Chunk<'a> {
+    sequence: u16,
+    total_length: u32,
+    data: &'a [u8],
+}
+
+impl<'a> Chunk<'a> {
+    pub const HEADER_LEN: usize = 12;
+
+    pub fn new(sequence: u16, total_length: u32, data: &'a [u8]) -> Self {
+        Self {
+            sequence,
+            total_length,
+            data,
+        }
+    }
+}
+
+impl Command for Chunk<'_> {
+    fn write_as_binary(&self, mut buf: impl Write) {
+        let _ = buf.write(&(StructureTypes::Chunk as u16).to_le_bytes());
+        let _ = buf.write(&((Self::HEADER_LEN + self.data.len()) as u16).to_le_bytes());
+        let _ = buf.write(&self.sequence.to_le_bytes());
+        let _ = buf.write(&[0u8; 2]); // reserved bytes
+        let _ = buf.write(&self.total_length.to_le_bytes());
+        let _ = buf.write(self.data);
     }
 }
 
@@ -168,6 +240,21 @@ impl Command for SaveConfiguration {
     }
 }
 
+pub struct GetActiveConfiguration();
+
+impl GetActiveConfiguration {
+    pub fn new() -> Self {
+        Self()
+    }
+}
+
+impl Command for GetActiveConfiguration {
+    fn write_as_binary(&self, mut buf: impl Write) {
+        let _ = buf.write(&(StructureTypes::GetActiveConfiguration as u16).to_le_bytes());
+        let _ = buf.write(&(4u16).to_le_bytes());
+    }
+}
+
 pub struct GetStoredConfiguration();
 
 impl GetStoredConfiguration {
@@ -212,7 +299,7 @@ mod tests {
     fn filter_works() {
         let mut buf = Vec::new();
         let config = Filters::default();
-        SetFilterConfiguration::new(&config)
+        SetFilterConfiguration::new(&config, &Capabilities::default())
             .unwrap()
             .write_as_binary(&mut buf);
         assert!(buf.len() > 0, "Command didn't write anything");
@@ -236,7 +323,8 @@ mod tests {
         let codec_config = Codec::default();
 
         let prep = SetPreprocessingConfiguration::new(&prep_config);
-        let filters = SetFilterConfiguration::new(&filters_config).unwrap();
+        let filters =
+            SetFilterConfiguration::new(&filters_config, &Capabilities::default()).unwrap();
         let codec = SetPcm3060Configuration::new(&codec_config);
         SetConfiguration::new(prep, filters, codec).write_as_binary(&mut buf);
         assert!(buf.len() > 0, "Command didn't write anything");
@@ -266,6 +354,17 @@ mod tests {
         assert_eq!(buf.as_slice(), &[7, 0, 4, 0], "Wrong data")
     }
 
+    #[test]
+    fn chunk_works() {
+        let mut buf = Vec::new();
+        Chunk::new(2, 600, &[1, 2, 3]).write_as_binary(&mut buf);
+        assert_eq!(
+            buf.as_slice(),
+            &[9, 0, 15, 0, 2, 0, 0, 0, 88, 2, 0, 0, 1, 2, 3],
+            "Wrong data"
+        )
+    }
+
     #[test]
     fn get_config_works() {
         let mut buf = Vec::new();
@@ -273,4 +372,12 @@ mod tests {
         assert!(buf.len() > 0, "Command didn't write anything");
         assert_eq!(buf.as_slice(), &[6, 0, 4, 0], "Wrong data")
     }
+
+    #[test]
+    fn get_active_config_works() {
+        let mut buf = Vec::new();
+        GetActiveConfiguration::new().write_as_binary(&mut buf);
+        assert!(buf.len() > 0, "Command didn't write anything");
+        assert_eq!(buf.as_slice(), &[5, 0, 4, 0], "Wrong data")
+    }
 }
@@ -0,0 +1,312 @@
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::{
+    commands::{NokCode, StructureTypes},
+    tlv::TlvReader,
+};
+
+/// Errors returned by the backend. They are serialized to the frontend as an object with a `kind`
+/// tag and a human readable `message`, plus any details specific to the kind of error.
+#[derive(Debug, Clone, Error)]
+pub enum Error {
+    #[error("Not connected")]
+    NotConnected,
+    #[error("Can't find device {serial_number}")]
+    DeviceNotFound { serial_number: String },
+    #[error("{context}: {source}")]
+    Usb {
+        context: String,
+        #[source]
+        source: rusb::Error,
+    },
+    #[error("{0}: Operation timed out")]
+    Timeout(String),
+    #[error("{0}: Cancelled")]
+    Cancelled(String),
+    #[error("{0}")]
+    Protocol(String),
+    #[error("The device rejected the request: {reason}")]
+    Nok {
+        code: u16,
+        reason: String,
+        tlv_type: Option<u16>,
+    },
+    #[error("{0}")]
+    Validation(String),
+    /// A filter which can't be run. `index` counts from zero over every filter, enabled or not,
+    /// and is None when a filter was checked on its own. `field` names the parameter.
+    #[error("{}{message}", .index.map(|i| format!("Filter {}: ", i + 1)).unwrap_or_default())]
+    InvalidFilter {
+        index: Option<usize>,
+        field: Option<&'static str>,
+        message: String,
+    },
+    #[error("The device applied a different configuration: {}", mismatches.join(", "))]
+    Verification { mismatches: Vec<String> },
+    #[error("Firmware supports protocol versions {minimum_supported_version} to {current_version}, but this client uses version {client_version}")]
+    VersionMismatch {
+        client_version: u16,
+        minimum_supported_version: u16,
+        current_version: u16,
+    },
+}
+
+impl Error {
+    /// Wraps a libusb error, keeping timeouts separate so the caller can retry them.
+    pub fn usb(context: &str, source: rusb::Error) -> Self {
+        match source {
+            rusb::Error::Timeout => Error::Timeout(context.to_owned()),
+            source => Error::Usb {
+                context: context.to_owned(),
+                source,
+            },
+        }
+    }
+
+    /// Decodes the value of a Nok container. The reference firmware leaves it empty, but a Nok
+    /// may echo back the structure it rejected, and the simulator adds an ErrorStatus structure.
+    pub fn from_nok(value: &[u8]) -> Self {
+        let mut code = NokCode::Unspecified as u16;
+        let mut message = None;
+        let mut tlv_type = None;
+        let mut reader = TlvReader::new(value);
+        // Whatever follows a malformed TLV can't be located, so decoding stops there
+        while let Ok((type_val, mut payload)) = reader.read_tlv() {
+            let status = (type_val == StructureTypes::ErrorStatus as u16)
+                .then(|| Self::read_error_status(&mut payload));
+            match status {
+                Some(Ok((status_code, offending, text))) => {
+                    code = status_code;
+                    if offending != 0 {
+                        tlv_type = Some(offending);
+                    }
+                    if !text.is_empty() {
+                        message = Some(text);
+                    }
+                }
+                _ => {
+                    tlv_type.get_or_insert(type_val);
+                }
+            }
+        }
+
+        let mut reason = message.unwrap_or_else(|| NokCode::describe(code).to_owned());
+        if let Some(t) = tlv_type {
+            reason = format!("{} (TLV type {:#x})", reason, t);
+        }
+        Error::Nok {
+            code,
+            reason,
+            tlv_type,
+        }
+    }
+
+    /// Reads the code, offending TLV type and optional NUL terminated message of an ErrorStatus.
+    fn read_error_status(payload: &mut TlvReader) -> Result<(u16, u16, String), Error> {
+        let code = payload.read_u16()?;
+        let offending = payload.read_u16()?;
+        let text = payload.read_bytes(payload.remaining())?;
+        let text = &text[..text.iter().position(|&c| c == 0).unwrap_or(text.len())];
+        Ok((code, offending, String::from_utf8_lossy(text).into_owned()))
+    }
+
+    /// A problem with one parameter of a filter. The index is filled in by `for_filter`.
+    pub fn filter_field(field: &'static str, message: String) -> Self {
+        Error::InvalidFilter {
+            index: None,
+            field: Some(field),
+            message,
+        }
+    }
+
+    /// Says which filter a validation error is about.
+    pub fn for_filter(self, index: usize) -> Self {
+        match self {
+            Error::InvalidFilter { field, message, .. } => Error::InvalidFilter {
+                index: Some(index),
+                field,
+                message,
+            },
+            Error::Validation(message) => Error::InvalidFilter {
+                index: Some(index),
+                field: None,
+                message,
+            },
+            e => e,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::NotConnected => "not_connected",
+            Error::DeviceNotFound { .. } => "device_not_found",
+            Error::Usb { .. } => "usb",
+            Error::Timeout(_) => "timeout",
+            Error::Cancelled(_) => "cancelled",
+            Error::Protocol(_) => "protocol",
+            Error::Nok { .. } => "nok",
+            Error::Validation(_) => "validation",
+            Error::InvalidFilter { .. } => "invalid_filter",
+            Error::Verification { .. } => "verification",
+            Error::VersionMismatch { .. } => "version_mismatch",
+        }
+    }
+}
+
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("kind", self.kind())?;
+        map.serialize_entry("message", &self.to_string())?;
+        match self {
+            Error::DeviceNotFound { serial_number } => {
+                map.serialize_entry("serial_number", serial_number)?;
+            }
+            Error::Usb { source, .. } => {
+                map.serialize_entry("access_denied", &(*source == rusb::Error::Access))?;
+            }
+            Error::Nok { code, tlv_type, .. } => {
+                map.serialize_entry("code", code)?;
+                map.serialize_entry("tlv_type", tlv_type)?;
+            }
+            Error::InvalidFilter { index, field, .. } => {
+                map.serialize_entry("index", index)?;
+                map.serialize_entry("field", field)?;
+            }
+            Error::Verification { mismatches } => {
+                map.serialize_entry("mismatches", mismatches)?;
+            }
+            Error::VersionMismatch {
+                client_version,
+                minimum_supported_version,
+                current_version,
+            } => {
+                map.serialize_entry("client_version", client_version)?;
+                map.serialize_entry("minimum_supported_version", minimum_supported_version)?;
+                map.serialize_entry("current_version", current_version)?;
+            }
+            _ => (),
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn serializes_as_tagged_object() {
+        assert_eq!(
+            serde_json::to_value(Error::NotConnected).unwrap(),
+            json!({"kind": "not_connected", "message": "Not connected"})
+        );
+        assert_eq!(
+            serde_json::to_value(Error::usb("Could not open", rusb::Error::Access)).unwrap(),
+            json!({
+                "kind": "usb",
+                "message": "Could not open: Access denied (insufficient permissions)",
+                "access_denied": true
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(Error::from_nok(&[0x2, 0x4, 8, 0, 3, 0, 0x2, 0x2])).unwrap(),
+            json!({
+                "kind": "nok",
+                "message": "The device rejected the request: unsupported structure (TLV type 0x202)",
+                "code": 3,
+                "tlv_type": 0x202
+            })
+        );
+    }
+
+    #[test]
+    fn filter_errors_name_the_filter() {
+        let err = Error::filter_field("q", "Q must be positive.".to_owned());
+        assert_eq!(err.to_string(), "Q must be positive.");
+
+        let err = err.for_filter(2);
+        assert_eq!(
+            serde_json::to_value(err).unwrap(),
+            json!({
+                "kind": "invalid_filter",
+                "message": "Filter 3: Q must be positive.",
+                "index": 2,
+                "field": "q"
+            })
+        );
+    }
+
+    #[test]
+    fn timeouts_are_separate() {
+        let err = Error::usb(
+            "Error reading from the configuration inteface",
+            rusb::Error::Timeout,
+        );
+        assert_eq!(err.kind(), "timeout");
+        assert_eq!(
+            err.to_string(),
+            "Error reading from the configuration inteface: Operation timed out"
+        );
+    }
+
+    #[test]
+    fn decodes_nok() {
+        let err = Error::from_nok(&[]);
+        assert_eq!(
+            err.to_string(),
+            "The device rejected the request: no reason given"
+        );
+
+        // The firmware supplied message takes priority over the generic description
+        let mut value = vec![0x2, 0x4, 19, 0, 5, 0, 0x1, 0x2];
+        value.extend_from_slice(b"Too many\0\0\0");
+        match Error::from_nok(&value) {
+            Error::Nok {
+                code,
+                reason,
+                tlv_type,
+            } => {
+                assert_eq!(code, NokCode::LimitExceeded as u16);
+                assert_eq!(reason, "Too many (TLV type 0x201)");
+                assert_eq!(tlv_type, Some(0x201));
+            }
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        // An echoed structure names the offending TLV
+        let err = Error::from_nok(&[0x0, 0x2, 8, 0, 0, 0, 0, 0]);
+        assert!(matches!(
+            err,
+            Error::Nok {
+                tlv_type: Some(0x200),
+                ..
+            }
+        ));
+
+        // Truncated nested TLVs are ignored
+        let err = Error::from_nok(&[0x2, 0x4, 40, 0, 4, 0]);
+        assert!(matches!(
+            err,
+            Error::Nok {
+                code: 0,
+                tlv_type: None,
+                ..
+            }
+        ));
+
+        // An ErrorStatus too short for its code still names the structure, and a trailing odd
+        // byte after a valid TLV is dropped
+        let err = Error::from_nok(&[0x2, 0x4, 5, 0, 4, 0x0]);
+        assert!(matches!(
+            err,
+            Error::Nok {
+                code: 0,
+                tlv_type: Some(0x402),
+                ..
+            }
+        ));
+    }
+}
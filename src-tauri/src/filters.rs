@@ -1,11 +1,15 @@
-use std::{f64::consts::PI, io::Read, marker::PhantomData};
+use std::{f64::consts::PI, marker::PhantomData};
 
-use byteorder::{LittleEndian, ReadBytesExt};
 use serde::{Deserialize, Serialize};
 
-use crate::low_level::{DeserializeFilter, Payload};
-
-const FS: f64 = 48000.0;
+use crate::{
+    capabilities::Capabilities,
+    commands::StructureTypes,
+    error::Error,
+    low_level::{read_filter, DeserializeFilter, Discriminant, Payload},
+    response,
+    tlv::{TlvReader, TlvStructure},
+};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FreqGainQualFilter<T: FilterName> {
@@ -17,9 +21,11 @@ pub struct FreqGainQualFilter<T: FilterName> {
 }
 
 impl<T: FilterName> FreqGainQualFilter<T> {
-    pub fn new(f0: f32, q: f32, db_gain: f32) -> Result<Self, String> {
+    pub fn new(f0: f32, q: f32, db_gain: f32) -> Result<Self, Error> {
         if q <= 0.0 {
-            return Err("Quality shall not be lower than 0.".to_owned());
+            return Err(Error::Validation(
+                "Quality shall not be lower than 0.".to_owned(),
+            ));
         }
 
         Ok(Self {
@@ -32,10 +38,10 @@ impl<T: FilterName> FreqGainQualFilter<T> {
 }
 
 impl<T: FilterName> DeserializeFilter for FreqGainQualFilter<T> {
-    fn from_reader(mut cur: impl Read) -> Result<Self, String> {
-        let f0 = cur.read_f32::<LittleEndian>().unwrap();
-        let db_gain = cur.read_f32::<LittleEndian>().unwrap();
-        let q = cur.read_f32::<LittleEndian>().unwrap();
+    fn from_reader(cur: &mut TlvReader) -> Result<Self, Error> {
+        let f0 = cur.read_f32()?;
+        let db_gain = cur.read_f32()?;
+        let q = cur.read_f32()?;
         Self::new(f0, q, db_gain)
     }
 }
@@ -49,9 +55,11 @@ pub struct FreqQualFilter<T: FilterName> {
 }
 
 impl<T: FilterName> FreqQualFilter<T> {
-    pub fn new(f0: f32, q: f32) -> Result<Self, String> {
+    pub fn new(f0: f32, q: f32) -> Result<Self, Error> {
         if q <= 0.0 {
-            return Err("Quality shall not be lower than 0.".to_owned());
+            return Err(Error::Validation(
+                "Quality shall not be lower than 0.".to_owned(),
+            ));
         }
 
         Ok(Self {
@@ -63,9 +71,9 @@ impl<T: FilterName> FreqQualFilter<T> {
 }
 
 impl<T: FilterName> DeserializeFilter for FreqQualFilter<T> {
-    fn from_reader(mut cur: impl Read) -> Result<Self, String> {
-        let f0 = cur.read_f32::<LittleEndian>().unwrap();
-        let q = cur.read_f32::<LittleEndian>().unwrap();
+    fn from_reader(cur: &mut TlvReader) -> Result<Self, Error> {
+        let f0 = cur.read_f32()?;
+        let q = cur.read_f32()?;
         Self::new(f0, q)
     }
 }
@@ -118,65 +126,160 @@ pub type PeakingFilter = FreqGainQualFilter<Peaking>;
 pub type LowShelfFilter = FreqGainQualFilter<LowShelf>;
 pub type HighShelfFilter = FreqGainQualFilter<HighShelf>;
 
+/// Checks a filter can be run by the connected firmware.
 pub trait Validate {
-    fn validate(&self) -> Result<(), String>;
+    fn validate(&self, capabilities: &Capabilities) -> Result<(), Error>;
 }
 
 impl Validate for FilterConfig {
-    fn validate(&self) -> Result<(), String> {
+    fn validate(&self, capabilities: &Capabilities) -> Result<(), Error> {
         match self {
-            FilterConfig::Lowpass(x) => x.validate(),
-            FilterConfig::Highpass(x) => x.validate(),
-            FilterConfig::BandpassSkirt(x) => x.validate(),
-            FilterConfig::BandpassPeak(x) => x.validate(),
-            FilterConfig::Notch(x) => x.validate(),
-            FilterConfig::Allpass(x) => x.validate(),
-            FilterConfig::Peaking(x) => x.validate(),
-            FilterConfig::LowShelf(x) => x.validate(),
-            FilterConfig::HighShelf(x) => x.validate(),
-            FilterConfig::CustomIIR(x) => x.validate(),
+            FilterConfig::Lowpass(x) => x.validate(capabilities),
+            FilterConfig::Highpass(x) => x.validate(capabilities),
+            FilterConfig::BandpassSkirt(x) => x.validate(capabilities),
+            FilterConfig::BandpassPeak(x) => x.validate(capabilities),
+            FilterConfig::Notch(x) => x.validate(capabilities),
+            FilterConfig::Allpass(x) => x.validate(capabilities),
+            FilterConfig::Peaking(x) => x.validate(capabilities),
+            FilterConfig::LowShelf(x) => x.validate(capabilities),
+            FilterConfig::HighShelf(x) => x.validate(capabilities),
+            FilterConfig::CustomIIR(x) => x.validate(capabilities),
         }
     }
 }
 
+/// Checks a parameter lies within `min..=max`, which also rules out NaN.
+fn check_range(
+    field: &'static str,
+    name: &str,
+    value: f32,
+    (min, max): (f32, f32),
+    unit: &str,
+) -> Result<(), Error> {
+    if !(min..=max).contains(&value) {
+        return Err(Error::filter_field(
+            field,
+            format!(
+                "{} must be between {}{} and {}{}, but is {}{}.",
+                name, min, unit, max, unit, value, unit
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the centre frequency and quality every parametric filter has.
+fn check_f0_q(f0: f32, q: f32, capabilities: &Capabilities) -> Result<(), Error> {
+    let nyquist = capabilities.nyquist();
+    if !(f0 > 0.0 && f0 < nyquist) {
+        return Err(Error::filter_field(
+            "f0",
+            format!(
+                "Frequency must be above 0 Hz and below {} Hz, but is {} Hz.",
+                nyquist, f0
+            ),
+        ));
+    }
+    let limits = &capabilities.filter_limits;
+    check_range("q", "Quality", q, (limits.min_q, limits.max_q), "")
+}
+
 impl<T: FilterName> Validate for FreqQualFilter<T> {
-    fn validate(&self) -> Result<(), String> {
-        if self.q <= 0.0 {
-            return Err("Quality can't be zero.".to_owned());
-        }
-        Ok(())
+    fn validate(&self, capabilities: &Capabilities) -> Result<(), Error> {
+        check_f0_q(self.f0, self.q, capabilities)
     }
 }
 
 impl<T: FilterName> Validate for FreqGainQualFilter<T> {
-    fn validate(&self) -> Result<(), String> {
-        if self.q <= 0.0 {
-            return Err("Quality can't be zero.".to_owned());
-        }
-        Ok(())
+    fn validate(&self, capabilities: &Capabilities) -> Result<(), Error> {
+        check_f0_q(self.f0, self.q, capabilities)?;
+        let max_gain = capabilities.filter_limits.max_gain_db;
+        check_range(
+            "db_gain",
+            "Gain",
+            self.db_gain,
+            (-max_gain, max_gain),
+            " dB",
+        )
     }
 }
 
 impl Validate for CustomIIRFilter {
-    fn validate(&self) -> Result<(), String> {
-        Ok(()) // TODO
+    fn validate(&self, _capabilities: &Capabilities) -> Result<(), Error> {
+        let coefficients = [
+            ("a0", self.a0),
+            ("a1", self.a1),
+            ("a2", self.a2),
+            ("b0", self.b0),
+            ("b1", self.b1),
+            ("b2", self.b2),
+        ];
+        if let Some((field, _)) = coefficients.iter().find(|(_, c)| !c.is_finite()) {
+            return Err(Error::filter_field(
+                field,
+                format!("{} must be a finite number.", field),
+            ));
+        }
+        if self.a0 == 0.0 {
+            return Err(Error::filter_field("a0", "a0 can't be zero.".to_owned()));
+        }
+        let analysis = response::analyze(self);
+        if !analysis.is_stable() {
+            let poles: Vec<String> = analysis.poles.iter().map(|p| p.to_string()).collect();
+            return Err(Error::Validation(format!(
+                "The poles at {} must lie inside the unit circle, the filter is unstable.",
+                poles.join(", ")
+            )));
+        }
+        Ok(())
     }
 }
 
 impl FilterConfig {
-    #[allow(dead_code)]
-    fn into_iir(self) -> CustomIIRFilter {
+    /// The centre or corner frequency, for filters which have one.
+    pub fn f0(&self) -> Option<f32> {
+        match self {
+            FilterConfig::Lowpass(x) => Some(x.f0),
+            FilterConfig::Highpass(x) => Some(x.f0),
+            FilterConfig::BandpassSkirt(x) => Some(x.f0),
+            FilterConfig::BandpassPeak(x) => Some(x.f0),
+            FilterConfig::Notch(x) => Some(x.f0),
+            FilterConfig::Allpass(x) => Some(x.f0),
+            FilterConfig::Peaking(x) => Some(x.f0),
+            FilterConfig::LowShelf(x) => Some(x.f0),
+            FilterConfig::HighShelf(x) => Some(x.f0),
+            FilterConfig::CustomIIR(_) => None,
+        }
+    }
+
+    /// The filter type as sent to the firmware.
+    pub fn discriminant(&self) -> u8 {
+        match self {
+            FilterConfig::Lowpass(_) => LowpassFilter::discriminant(),
+            FilterConfig::Highpass(_) => HighpassFilter::discriminant(),
+            FilterConfig::BandpassSkirt(_) => BandpassSkirtFilter::discriminant(),
+            FilterConfig::BandpassPeak(_) => BandpassPeakFilter::discriminant(),
+            FilterConfig::Notch(_) => NotchFilter::discriminant(),
+            FilterConfig::Allpass(_) => AllpassFilter::discriminant(),
+            FilterConfig::Peaking(_) => PeakingFilter::discriminant(),
+            FilterConfig::LowShelf(_) => LowShelfFilter::discriminant(),
+            FilterConfig::HighShelf(_) => HighShelfFilter::discriminant(),
+            FilterConfig::CustomIIR(_) => CustomIIRFilter::discriminant(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
         match self {
-            FilterConfig::Lowpass(x) => x.into(),
-            FilterConfig::Highpass(x) => x.into(),
-            FilterConfig::BandpassSkirt(x) => x.into(),
-            FilterConfig::BandpassPeak(x) => x.into(),
-            FilterConfig::Notch(x) => x.into(),
-            FilterConfig::Allpass(x) => x.into(),
-            FilterConfig::Peaking(x) => x.into(),
-            FilterConfig::LowShelf(x) => x.into(),
-            FilterConfig::HighShelf(x) => x.into(),
-            FilterConfig::CustomIIR(x) => x.into(),
+            FilterConfig::Lowpass(_) => "Lowpass",
+            FilterConfig::Highpass(_) => "Highpass",
+            FilterConfig::BandpassSkirt(_) => "BandpassSkirt",
+            FilterConfig::BandpassPeak(_) => "BandpassPeak",
+            FilterConfig::Notch(_) => "Notch",
+            FilterConfig::Allpass(_) => "Allpass",
+            FilterConfig::Peaking(_) => "Peaking",
+            FilterConfig::LowShelf(_) => "LowShelf",
+            FilterConfig::HighShelf(_) => "HighShelf",
+            FilterConfig::CustomIIR(_) => "CustomIIR",
         }
     }
 
@@ -256,7 +359,7 @@ impl Into<FilterConfig> for CustomIIRFilter {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SavedFilter {
     enabled: bool,
 
@@ -270,17 +373,18 @@ impl SavedFilter {
     }
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct Filters(Vec<SavedFilter>);
 
 impl Filters {
-    pub fn to_payload(&self) -> Vec<u8> {
-        self.0
-            .iter()
-            .filter(|f| f.enabled)
-            .map(|f| f.filter.payload())
-            .flatten()
-            .collect()
+    /// The filters which will be sent to the device.
+    pub fn enabled(&self) -> impl Iterator<Item = &FilterConfig> {
+        self.0.iter().filter(|f| f.enabled).map(|f| &f.filter)
+    }
+
+    /// Every filter, enabled or not, in the order they are applied.
+    pub fn all(&self) -> impl Iterator<Item = (&FilterConfig, bool)> {
+        self.0.iter().map(|f| (&f.filter, f.enabled))
     }
 
     pub fn add(&mut self, filter: FilterConfig, enabled: bool) {
@@ -288,9 +392,100 @@ impl Filters {
     }
 }
 
+/// Only enabled filters are sent to the device, so decoding gives back just those.
+impl TlvStructure for Filters {
+    const TYPE: u16 = StructureTypes::FilterConfiguration as u16;
+
+    fn encode_value(&self, buf: &mut Vec<u8>) {
+        for filter in self.enabled() {
+            buf.extend(filter.payload());
+        }
+    }
+
+    fn decode_value(cur: &mut TlvReader) -> Result<Self, Error> {
+        let mut filters = Filters::default();
+        while !cur.is_empty() {
+            filters.add(read_filter(cur)?, true);
+        }
+        Ok(filters)
+    }
+}
+
+/// The coefficients of the enabled filters at one sample rate.
+#[derive(Serialize, Debug, Clone)]
+pub struct CoefficientSet {
+    pub sample_rate: u32,
+    pub filters: Vec<CustomIIRFilter>,
+}
+
+impl Filters {
+    /// Calculates the enabled filters' coefficients at each sample rate the device plays.
+    pub fn coefficients(&self, sample_rates: &[u32]) -> Vec<CoefficientSet> {
+        sample_rates
+            .iter()
+            .map(|&sample_rate| CoefficientSet {
+                sample_rate,
+                filters: self
+                    .enabled()
+                    .map(|f| f.to_custom(f64::from(sample_rate)))
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Describes each enabled filter whose f0 is at or above the Nyquist frequency of one of the
+    /// sample rates, where the filter can't do what it was set up to.
+    pub fn nyquist_warnings(&self, sample_rates: &[u32]) -> Vec<String> {
+        let mut warnings = Vec::new();
+        // Numbered like the filter cards, counting the disabled ones
+        for (i, (filter, _)) in self.all().enumerate().filter(|(_, (_, enabled))| *enabled) {
+            let Some(f0) = filter.f0() else {
+                continue;
+            };
+            for &sample_rate in sample_rates {
+                let nyquist = sample_rate as f32 / 2.0;
+                if f0 >= nyquist {
+                    warnings.push(format!(
+                        "Filter {} has an f0 of {} Hz, above the {} Hz limit when playing at {} Hz.",
+                        i + 1,
+                        f0,
+                        nyquist,
+                        sample_rate
+                    ));
+                }
+            }
+        }
+        warnings
+    }
+}
+
 impl Validate for Filters {
-    fn validate(&self) -> Result<(), String> {
-        self.0.iter().map(|f| f.filter.validate()).collect()
+    fn validate(&self, capabilities: &Capabilities) -> Result<(), Error> {
+        for (i, (filter, enabled)) in self.all().enumerate() {
+            if enabled && !capabilities.filter_types.contains(&filter.discriminant()) {
+                return Err(Error::filter_field(
+                    "filter_type",
+                    format!("This firmware doesn't support {} filters.", filter.name()),
+                )
+                .for_filter(i));
+            }
+        }
+        if let Some(max_filters) = capabilities.max_filters {
+            let count = self.enabled().count();
+            if count > usize::from(max_filters) {
+                return Err(Error::Validation(format!(
+                    "This firmware supports up to {} filters, but {} are enabled.",
+                    max_filters, count
+                )));
+            }
+        }
+        // Disabled filters are never sent, so they may be parked out of range
+        self.0
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.enabled)
+            .map(|(i, f)| f.filter.validate(capabilities).map_err(|e| e.for_filter(i)))
+            .collect()
     }
 }
 
@@ -341,13 +536,38 @@ pub struct HighShelf();
 
 impl FilterName for HighShelf {}
 
+/// Calculates the biquad coefficients a filter runs as at a sample rate, following the RBJ
+/// Audio EQ Cookbook.
 pub trait ToCustomIIR {
-    fn to_custom(&self) -> CustomIIRFilter;
+    fn to_custom(&self, sample_rate: f64) -> CustomIIRFilter;
+}
+
+impl ToCustomIIR for CustomIIRFilter {
+    fn to_custom(&self, _sample_rate: f64) -> CustomIIRFilter {
+        self.clone()
+    }
 }
 
-impl Into<CustomIIRFilter> for LowpassFilter {
-    fn into(self) -> CustomIIRFilter {
-        let w0: f64 = 2.0 * PI * (f64::from(self.f0)) / FS;
+impl ToCustomIIR for FilterConfig {
+    fn to_custom(&self, sample_rate: f64) -> CustomIIRFilter {
+        match self {
+            FilterConfig::Lowpass(x) => x.to_custom(sample_rate),
+            FilterConfig::Highpass(x) => x.to_custom(sample_rate),
+            FilterConfig::BandpassSkirt(x) => x.to_custom(sample_rate),
+            FilterConfig::BandpassPeak(x) => x.to_custom(sample_rate),
+            FilterConfig::Notch(x) => x.to_custom(sample_rate),
+            FilterConfig::Allpass(x) => x.to_custom(sample_rate),
+            FilterConfig::Peaking(x) => x.to_custom(sample_rate),
+            FilterConfig::LowShelf(x) => x.to_custom(sample_rate),
+            FilterConfig::HighShelf(x) => x.to_custom(sample_rate),
+            FilterConfig::CustomIIR(x) => x.to_custom(sample_rate),
+        }
+    }
+}
+
+impl ToCustomIIR for LowpassFilter {
+    fn to_custom(&self, sample_rate: f64) -> CustomIIRFilter {
+        let w0: f64 = 2.0 * PI * f64::from(self.f0) / sample_rate;
         let cosw0 = w0.cos();
         let sinw0 = w0.sin();
         let alpha = sinw0 / (2.0 * f64::from(self.q));
@@ -362,9 +582,9 @@ impl Into<CustomIIRFilter> for LowpassFilter {
     }
 }
 
-impl Into<CustomIIRFilter> for HighpassFilter {
-    fn into(self) -> CustomIIRFilter {
-        let w0: f64 = 2.0 * PI * (f64::from(self.f0)) / FS;
+impl ToCustomIIR for HighpassFilter {
+    fn to_custom(&self, sample_rate: f64) -> CustomIIRFilter {
+        let w0: f64 = 2.0 * PI * f64::from(self.f0) / sample_rate;
         let cosw0 = w0.cos();
         let sinw0 = w0.sin();
         let alpha = sinw0 / (2.0 * f64::from(self.q));
@@ -379,9 +599,9 @@ impl Into<CustomIIRFilter> for HighpassFilter {
     }
 }
 
-impl Into<CustomIIRFilter> for BandpassSkirtFilter {
-    fn into(self) -> CustomIIRFilter {
-        let w0: f64 = 2.0 * PI * (f64::from(self.f0)) / FS;
+impl ToCustomIIR for BandpassSkirtFilter {
+    fn to_custom(&self, sample_rate: f64) -> CustomIIRFilter {
+        let w0: f64 = 2.0 * PI * f64::from(self.f0) / sample_rate;
         let cosw0 = w0.cos();
         let sinw0 = w0.sin();
         let alpha = sinw0 / (2.0 * f64::from(self.q));
@@ -396,9 +616,9 @@ impl Into<CustomIIRFilter> for BandpassSkirtFilter {
     }
 }
 
-impl Into<CustomIIRFilter> for BandpassPeakFilter {
-    fn into(self) -> CustomIIRFilter {
-        let w0: f64 = 2.0 * PI * (f64::from(self.f0)) / FS;
+impl ToCustomIIR for BandpassPeakFilter {
+    fn to_custom(&self, sample_rate: f64) -> CustomIIRFilter {
+        let w0: f64 = 2.0 * PI * f64::from(self.f0) / sample_rate;
         let cosw0 = w0.cos();
         let sinw0 = w0.sin();
         let alpha = sinw0 / (2.0 * f64::from(self.q));
@@ -413,9 +633,9 @@ impl Into<CustomIIRFilter> for BandpassPeakFilter {
     }
 }
 
-impl Into<CustomIIRFilter> for NotchFilter {
-    fn into(self) -> CustomIIRFilter {
-        let w0: f64 = 2.0 * PI * (f64::from(self.f0)) / FS;
+impl ToCustomIIR for NotchFilter {
+    fn to_custom(&self, sample_rate: f64) -> CustomIIRFilter {
+        let w0: f64 = 2.0 * PI * f64::from(self.f0) / sample_rate;
         let cosw0 = w0.cos();
         let sinw0 = w0.sin();
         let alpha = sinw0 / (2.0 * f64::from(self.q));
@@ -430,9 +650,9 @@ impl Into<CustomIIRFilter> for NotchFilter {
     }
 }
 
-impl Into<CustomIIRFilter> for AllpassFilter {
-    fn into(self) -> CustomIIRFilter {
-        let w0: f64 = 2.0 * PI * (f64::from(self.f0)) / FS;
+impl ToCustomIIR for AllpassFilter {
+    fn to_custom(&self, sample_rate: f64) -> CustomIIRFilter {
+        let w0: f64 = 2.0 * PI * f64::from(self.f0) / sample_rate;
         let cosw0 = w0.cos();
         let sinw0 = w0.sin();
         let alpha = sinw0 / (2.0 * f64::from(self.q));
@@ -447,9 +667,9 @@ impl Into<CustomIIRFilter> for AllpassFilter {
     }
 }
 
-impl Into<CustomIIRFilter> for PeakingFilter {
-    fn into(self) -> CustomIIRFilter {
-        let w0: f64 = 2.0 * PI * (f64::from(self.f0)) / FS;
+impl ToCustomIIR for PeakingFilter {
+    fn to_custom(&self, sample_rate: f64) -> CustomIIRFilter {
+        let w0: f64 = 2.0 * PI * f64::from(self.f0) / sample_rate;
         let cosw0 = w0.cos();
         let sinw0 = w0.sin();
         let alpha = sinw0 / (2.0 * f64::from(self.q));
@@ -466,9 +686,9 @@ impl Into<CustomIIRFilter> for PeakingFilter {
     }
 }
 
-impl Into<CustomIIRFilter> for LowShelfFilter {
-    fn into(self) -> CustomIIRFilter {
-        let w0: f64 = 2.0 * PI * (f64::from(self.f0)) / FS;
+impl ToCustomIIR for LowShelfFilter {
+    fn to_custom(&self, sample_rate: f64) -> CustomIIRFilter {
+        let w0: f64 = 2.0 * PI * f64::from(self.f0) / sample_rate;
         let cosw0 = w0.cos();
         let sinw0 = w0.sin();
         let alpha = sinw0 / (2.0 * f64::from(self.q));
@@ -486,9 +706,9 @@ impl Into<CustomIIRFilter> for LowShelfFilter {
     }
 }
 
-impl Into<CustomIIRFilter> for HighShelfFilter {
-    fn into(self) -> CustomIIRFilter {
-        let w0: f64 = 2.0 * PI * (f64::from(self.f0)) / FS;
+impl ToCustomIIR for HighShelfFilter {
+    fn to_custom(&self, sample_rate: f64) -> CustomIIRFilter {
+        let w0: f64 = 2.0 * PI * f64::from(self.f0) / sample_rate;
         let cosw0 = w0.cos();
         let sinw0 = w0.sin();
         let alpha = sinw0 / (2.0 * f64::from(self.q));
@@ -505,3 +725,130 @@ impl Into<CustomIIRFilter> for HighShelfFilter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coefficients_follow_the_sample_rate() {
+        let mut filters = Filters::default();
+        filters.add(LowpassFilter::new(1000.0, 0.7).unwrap().into(), true);
+        filters.add(HighpassFilter::new(100.0, 0.7).unwrap().into(), false);
+        let sets = filters.coefficients(&[44100, 96000]);
+
+        assert_eq!(sets.len(), 2);
+        assert_eq!(sets[0].sample_rate, 44100);
+        assert_eq!(sets[0].filters.len(), 1);
+        // The same cutoff sits closer to DC at the higher rate, so the poles move towards z = 1
+        assert!(sets[1].filters[0].a1 < sets[0].filters[0].a1);
+    }
+
+    #[test]
+    fn custom_filters_must_be_stable() {
+        let capabilities = Capabilities::default();
+        let error =
+            |filter: CustomIIRFilter| filter.validate(&capabilities).unwrap_err().to_string();
+
+        assert!(PeakingFilter::new(1000.0, 0.7, 6.0)
+            .unwrap()
+            .to_custom(48000.0)
+            .validate(&capabilities)
+            .is_ok());
+        assert_eq!(
+            error(CustomIIRFilter::new(0.0, 0.0, 0.0, 1.0, 0.0, 0.0)),
+            "a0 can't be zero."
+        );
+        assert_eq!(
+            error(CustomIIRFilter::new(1.0, f64::NAN, 0.0, 1.0, 0.0, 0.0)),
+            "a1 must be a finite number."
+        );
+        assert_eq!(
+            error(CustomIIRFilter::new(1.0, 0.0, 1.0, 1.0, 0.0, 0.0)),
+            "The poles at 0.0000+1.0000j, 0.0000-1.0000j must lie inside the unit \
+             circle, the filter is unstable."
+        );
+        // Poles outside the unit circle, at 1.25 and 0.8
+        assert!(error(CustomIIRFilter::new(1.0, -2.05, 1.0, 1.0, 0.0, 0.0)).contains("1.2500"));
+    }
+
+    #[test]
+    fn parametric_filters_must_be_in_range() {
+        let capabilities = Capabilities::default();
+        let error = |filter: FilterConfig| {
+            let mut filters = Filters::default();
+            filters.add(PeakingFilter::new(1000.0, 0.7, 3.0).unwrap().into(), false);
+            filters.add(filter, true);
+            filters.validate(&capabilities).unwrap_err()
+        };
+
+        assert!(matches!(
+            error(PeakingFilter::new(48000.0, 0.7, 3.0).unwrap().into()),
+            Error::InvalidFilter {
+                index: Some(1),
+                field: Some("f0"),
+                ..
+            }
+        ));
+        assert_eq!(
+            error(LowpassFilter::new(f32::NAN, 0.7).unwrap().into()).to_string(),
+            "Filter 2: Frequency must be above 0 Hz and below 48000 Hz, but is NaN Hz."
+        );
+        assert_eq!(
+            error(NotchFilter::new(1000.0, 500.0).unwrap().into()).to_string(),
+            "Filter 2: Quality must be between 0.01 and 100, but is 500."
+        );
+        assert_eq!(
+            error(HighShelfFilter::new(1000.0, 0.7, -40.0).unwrap().into()).to_string(),
+            "Filter 2: Gain must be between -30 dB and 30 dB, but is -40 dB."
+        );
+        assert!(matches!(
+            error(
+                LowShelfFilter::new(1000.0, 0.7, f32::INFINITY)
+                    .unwrap()
+                    .into()
+            ),
+            Error::InvalidFilter {
+                field: Some("db_gain"),
+                ..
+            }
+        ));
+
+        // A disabled filter isn't sent, so it may be out of range
+        let mut filters = Filters::default();
+        filters.add(
+            PeakingFilter::new(1000.0, 500.0, 3.0).unwrap().into(),
+            false,
+        );
+        assert!(filters.validate(&capabilities).is_ok());
+
+        // The limits follow the firmware
+        let mut capabilities = Capabilities {
+            sample_rates: vec![48000, 96000],
+            ..Default::default()
+        };
+        let mut filters = Filters::default();
+        filters.add(PeakingFilter::new(30000.0, 0.7, 3.0).unwrap().into(), true);
+        assert!(filters.validate(&capabilities).is_ok());
+        capabilities.filter_limits.max_gain_db = 2.0;
+        assert!(filters.validate(&capabilities).is_err());
+    }
+
+    #[test]
+    fn filters_above_nyquist_are_reported() {
+        let mut filters = Filters::default();
+        filters.add(PeakingFilter::new(30000.0, 0.7, 3.0).unwrap().into(), false);
+        filters.add(PeakingFilter::new(1000.0, 0.7, 3.0).unwrap().into(), true);
+        filters.add(PeakingFilter::new(23000.0, 0.7, 3.0).unwrap().into(), true);
+        filters.add(
+            CustomIIRFilter::new(1.0, 0.0, 0.0, 1.0, 0.0, 0.0).into(),
+            true,
+        );
+
+        assert!(filters.nyquist_warnings(&[48000, 96000]).is_empty());
+        assert_eq!(
+            filters.nyquist_warnings(&[44100, 48000, 96000]),
+            vec!["Filter 3 has an f0 of 23000 Hz, above the 22050 Hz limit when playing at 44100 Hz."]
+        );
+    }
+}
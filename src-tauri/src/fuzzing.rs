@@ -0,0 +1,80 @@
+//! Entry points for the cargo-fuzz targets in `fuzz/`. These only exist when building with
+//! `--cfg fuzzing` and must never panic, whatever the input.
+
+use std::collections::VecDeque;
+
+use crate::error::Error;
+use crate::low_level::read_filter;
+use crate::tlv::TlvReader;
+use crate::transport::{read_ok_response, TransferMode, Transport};
+use crate::{parse_config, VersionInfo};
+
+pub fn parse_config_response(data: &[u8]) {
+    let _ = parse_config(data);
+}
+
+pub fn parse_filter(data: &[u8]) {
+    let mut cur = TlvReader::new(data);
+    while !cur.is_empty() {
+        if read_filter(&mut cur).is_err() {
+            break;
+        }
+    }
+}
+
+pub fn parse_version_response(data: &[u8]) {
+    let _ = VersionInfo::from_buf(data);
+    let _ = TransferMode::from_version_response(data);
+}
+
+pub fn parse_nok(data: &[u8]) {
+    let _ = Error::from_nok(data).to_string();
+}
+
+/// Feeds the input to `read_ok_response` as a sequence of packets, the first byte of each packet
+/// gives its length. This covers reassembly through to the OK or NOK the device replied with.
+pub fn reassemble_response(data: &[u8]) {
+    let mut packets = VecDeque::new();
+    let mut rest = data;
+    while let Some((&len, tail)) = rest.split_first() {
+        let len = usize::from(len).min(tail.len());
+        packets.push_back(tail[..len].to_vec());
+        rest = &tail[len..];
+    }
+    let _ = read_ok_response(&mut PacketTransport { packets }).map_err(|e| e.to_string());
+}
+
+#[derive(Debug)]
+struct PacketTransport {
+    packets: VecDeque<Vec<u8>>,
+}
+
+impl Transport for PacketTransport {
+    fn write_frame(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        Ok(buf.len())
+    }
+
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let packet = self.packets.pop_front().ok_or(Error::Timeout(
+            "Error reading from the configuration inteface".to_owned(),
+        ))?;
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+
+    fn write_control(
+        &mut self,
+        _request_type: u8,
+        _request: u8,
+        _value: u16,
+        _index: u16,
+        _buf: &[u8],
+    ) -> Result<usize, Error> {
+        Ok(0)
+    }
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+}
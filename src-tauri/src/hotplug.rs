@@ -0,0 +1,436 @@
+use std::{
+    collections::HashMap,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+use rusb::{Context, Device, Hotplug, HotplugBuilder, UsbContext};
+use serde::Serialize;
+use tauri::{AppHandle, Emitter, Manager};
+
+use crate::{
+    registry::{DeviceModel, Registry},
+    worker::{UsbWorker, COMMAND_TIMEOUT},
+    ConnectionState,
+};
+
+/// Emitted with a `DeviceEvent` when headphones are plugged in.
+pub const DEVICE_ARRIVED: &str = "device-arrived";
+/// Emitted with a `DeviceEvent` when headphones are unplugged.
+pub const DEVICE_REMOVED: &str = "device-removed";
+/// Emitted with an `InaccessibleDevice` when headphones are plugged in but can't be opened.
+pub const DEVICE_INACCESSIBLE: &str = "device-inaccessible";
+/// Emitted with an `InaccessibleDevice` when it is unplugged, or can be opened after all.
+pub const INACCESSIBLE_DEVICE_REMOVED: &str = "inaccessible-device-removed";
+
+/// How often devices are enumerated where libusb can't report hotplug events, and how often
+/// devices we couldn't open are retried. Fixing the permissions doesn't raise a hotplug event.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How long to wait for libusb events before handling the devices which arrived.
+const EVENT_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DeviceEvent {
+    pub address: u16,
+    pub serial_number: String,
+}
+
+/// A registered device we aren't allowed to open, on Linux usually for want of udev rules.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct InaccessibleDevice {
+    pub address: u16,
+    pub name: String,
+}
+
+pub fn device_address<T: UsbContext>(device: &Device<T>) -> u16 {
+    ((device.bus_number() as u16) << 8) | (device.address() as u16)
+}
+
+/// Reads the serial number of a new device. This means opening it, which fails with
+/// `rusb::Error::Access` if we don't have permission to.
+fn read_serial_number<T: UsbContext>(device: &Device<T>) -> rusb::Result<String> {
+    let address = device_address(device);
+    info!("New device found at address {}", address);
+
+    let device_desc = device.device_descriptor()?;
+    let handle = device.open().inspect_err(|e| match e {
+        rusb::Error::Access => warn!("No permission to open the device at address {}", address),
+        e => error!("Open failed {}", e),
+    })?;
+
+    let serial_number_string_index = device_desc
+        .serial_number_string_index()
+        .ok_or(rusb::Error::NotFound)?;
+    match handle.read_string_descriptor_ascii(serial_number_string_index) {
+        Ok(sn) => {
+            info!("Device {} has serial number {}", address, sn);
+            Ok(sn)
+        }
+        Err(e) => {
+            error!("Get serial number failed {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Records a device which has arrived, returning the event to emit if it is new.
+fn arrived(
+    serial_numbers: &mut HashMap<u16, String>,
+    address: u16,
+    serial_number: String,
+) -> Option<DeviceEvent> {
+    if serial_numbers.get(&address) == Some(&serial_number) {
+        return None;
+    }
+    serial_numbers.insert(address, serial_number.clone());
+    Some(DeviceEvent {
+        address,
+        serial_number,
+    })
+}
+
+/// Forgets a device which has left, returning the event to emit if it was known.
+fn removed(serial_numbers: &mut HashMap<u16, String>, address: u16) -> Option<DeviceEvent> {
+    let serial_number = serial_numbers.remove(&address)?;
+    info!(
+        "The device {} at address {} was disconnected",
+        serial_number, address
+    );
+    Some(DeviceEvent {
+        address,
+        serial_number,
+    })
+}
+
+/// Brings the known devices up to date with the addresses now present. Serial numbers are only
+/// read for devices we haven't seen before, as that means opening them.
+fn reconcile(
+    serial_numbers: &mut HashMap<u16, String>,
+    present: &[u16],
+    mut read_serial_number: impl FnMut(u16) -> Option<String>,
+) -> (Vec<DeviceEvent>, Vec<DeviceEvent>) {
+    let gone: Vec<u16> = serial_numbers
+        .keys()
+        .filter(|address| !present.contains(address))
+        .copied()
+        .collect();
+    let removals = gone
+        .into_iter()
+        .filter_map(|address| removed(serial_numbers, address))
+        .collect();
+
+    let mut arrivals = Vec::new();
+    for &address in present {
+        if serial_numbers.contains_key(&address) {
+            continue;
+        }
+        if let Some(event) =
+            read_serial_number(address).and_then(|sn| arrived(serial_numbers, address, sn))
+        {
+            arrivals.push(event);
+        }
+    }
+    (arrivals, removals)
+}
+
+enum Change {
+    Arrived(Device<Context>),
+    Left(u16),
+}
+
+/// Forwards hotplug events to the watcher thread. Devices can't be opened from inside a libusb
+/// callback, so the serial numbers are read once `handle_events` has returned.
+struct Callback(mpsc::Sender<Change>);
+
+impl Hotplug<Context> for Callback {
+    fn device_arrived(&mut self, device: Device<Context>) {
+        let _ = self.0.send(Change::Arrived(device));
+    }
+
+    fn device_left(&mut self, device: Device<Context>) {
+        let _ = self.0.send(Change::Left(device_address(&device)));
+    }
+}
+
+struct Watcher {
+    app: AppHandle,
+    context: Context,
+    registry: Registry,
+}
+
+impl Watcher {
+    fn emit<S: Serialize + Clone>(&self, event: &str, devices: Vec<S>) {
+        for device in devices {
+            if let Err(e) = self.app.emit(event, device) {
+                warn!("Failed to emit {}: {}", event, e);
+            }
+        }
+    }
+
+    fn model<T: UsbContext>(&self, device: &Device<T>) -> Option<&DeviceModel> {
+        let desc = device.device_descriptor().ok()?;
+        self.registry.find(desc.vendor_id(), desc.product_id())
+    }
+
+    /// Updates the known devices on the USB worker. This waits for as long as it takes, as
+    /// dropping a change would leave the device list out of date.
+    fn update<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut ConnectionState) -> R + Send + 'static,
+    ) -> Option<R> {
+        let worker = self.app.state::<UsbWorker>();
+        match worker.call("update devices", Duration::MAX, |c| Ok(f(c))) {
+            Ok(r) => Some(r),
+            Err(e) => {
+                warn!("Failed to update the device list: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Lets the sessions reconnect to their devices if they were the ones that came back, before
+    /// the frontend hears about it.
+    fn reconnect(&self) {
+        let worker = self.app.state::<UsbWorker>();
+        if let Err(e) = worker.call("reconnect", COMMAND_TIMEOUT, |c| {
+            c.check_connections();
+            Ok(())
+        }) {
+            warn!("Failed to reconnect: {}", e);
+        }
+    }
+
+    fn watch(&self) -> rusb::Result<()> {
+        let (sender, changes) = mpsc::channel();
+        // Every device is reported, the registry decides which ones are ours
+        let mut builder = HotplugBuilder::new();
+        builder.enumerate(true);
+        let _registration = builder.register(&self.context, Box::new(Callback(sender)))?;
+        info!("Watching for devices with hotplug events");
+
+        let mut retry_inaccessible = false;
+        let mut last_retry = Instant::now();
+        loop {
+            if retry_inaccessible && last_retry.elapsed() >= POLL_INTERVAL {
+                retry_inaccessible = self.rescan();
+                last_retry = Instant::now();
+            }
+
+            for change in changes.try_iter() {
+                match change {
+                    Change::Arrived(device) => {
+                        let name = match self.model(&device) {
+                            Some(model) => model.name.clone(),
+                            None => continue,
+                        };
+                        let address = device_address(&device);
+                        let sn = match read_serial_number(&device) {
+                            Ok(sn) => sn,
+                            Err(rusb::Error::Access) => {
+                                let device = InaccessibleDevice { address, name };
+                                let inaccessible = device.clone();
+                                self.update(move |c| {
+                                    c.inaccessible.insert(address, inaccessible);
+                                });
+                                self.emit(DEVICE_INACCESSIBLE, vec![device]);
+                                retry_inaccessible = true;
+                                continue;
+                            }
+                            Err(_) => continue,
+                        };
+                        if let Some(event) = self
+                            .update(move |c| {
+                                c.device_names.insert(sn.clone(), name);
+                                arrived(&mut c.serial_numbers, address, sn)
+                            })
+                            .flatten()
+                        {
+                            self.reconnect();
+                            self.emit(DEVICE_ARRIVED, vec![event]);
+                        }
+                    }
+                    Change::Left(address) => {
+                        let Some((inaccessible, event)) = self.update(move |c| {
+                            let inaccessible = c.inaccessible.remove(&address);
+                            (inaccessible, removed(&mut c.serial_numbers, address))
+                        }) else {
+                            continue;
+                        };
+                        self.emit(
+                            INACCESSIBLE_DEVICE_REMOVED,
+                            inaccessible.into_iter().collect(),
+                        );
+                        self.emit(DEVICE_REMOVED, event.into_iter().collect());
+                    }
+                }
+            }
+            self.context.handle_events(Some(EVENT_TIMEOUT))?;
+        }
+    }
+
+    /// Enumerates the devices, reporting any changes since the last scan. Returns whether any
+    /// devices are still inaccessible.
+    fn rescan(&self) -> bool {
+        let Ok(devices) = self.context.devices() else {
+            return true;
+        };
+        let devices: HashMap<u16, (Device<Context>, String)> = devices
+            .iter()
+            .filter_map(|d| {
+                let name = self.model(&d)?.name.clone();
+                Some((device_address(&d), (d, name)))
+            })
+            .collect();
+        let present: Vec<u16> = devices.keys().copied().collect();
+
+        // Read the serial numbers of new devices without holding up the USB worker
+        let Some(mut known) = self.update(|c| c.serial_numbers.clone()) else {
+            return true;
+        };
+        let mut inaccessible = HashMap::new();
+        let (arrivals, removals) = reconcile(&mut known, &present, |address| {
+            let (device, name) = &devices[&address];
+            match read_serial_number(device) {
+                Ok(sn) => Some(sn),
+                Err(rusb::Error::Access) => {
+                    let name = name.clone();
+                    inaccessible.insert(address, InaccessibleDevice { address, name });
+                    None
+                }
+                Err(_) => None,
+            }
+        });
+        let names: Vec<(String, String)> = arrivals
+            .iter()
+            .map(|e| (e.serial_number.clone(), devices[&e.address].1.clone()))
+            .collect();
+        let still_inaccessible = !inaccessible.is_empty();
+        let Some((new, gone)) = self.update(move |c| {
+            c.serial_numbers = known;
+            c.device_names.extend(names);
+            // Only report devices the first time we fail to open them
+            let new: Vec<InaccessibleDevice> = inaccessible
+                .values()
+                .filter(|d| !c.inaccessible.contains_key(&d.address))
+                .cloned()
+                .collect();
+            let gone: Vec<InaccessibleDevice> =
+                std::mem::replace(&mut c.inaccessible, inaccessible)
+                    .into_values()
+                    .filter(|d| !c.inaccessible.contains_key(&d.address))
+                    .collect();
+            (new, gone)
+        }) else {
+            return true;
+        };
+
+        self.emit(DEVICE_INACCESSIBLE, new);
+        self.emit(INACCESSIBLE_DEVICE_REMOVED, gone);
+
+        self.emit(DEVICE_REMOVED, removals);
+        if !arrivals.is_empty() {
+            self.reconnect();
+        }
+        self.emit(DEVICE_ARRIVED, arrivals);
+        still_inaccessible
+    }
+
+    fn poll(&self) {
+        info!("Hotplug events are unavailable, polling for devices");
+        loop {
+            self.rescan();
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Creates the long-lived libusb context and starts tracking devices in the background, keeping
+/// `ConnectionState.serial_numbers` up to date and emitting events as devices come and go.
+pub fn spawn(app: AppHandle, registry: Registry) {
+    let context = match Context::new() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Failed to create a libusb context: {}", e);
+            return;
+        }
+    };
+    let shared = (context.clone(), registry.clone());
+    let result = app
+        .state::<UsbWorker>()
+        .call("share context", Duration::MAX, move |c| {
+            (c.context, c.registry) = (Some(shared.0), shared.1);
+            Ok(())
+        });
+    if let Err(e) = result {
+        error!("Failed to share the libusb context: {}", e);
+    }
+
+    let watcher = Watcher {
+        app,
+        context,
+        registry,
+    };
+    let result = thread::Builder::new()
+        .name("usb-hotplug".to_owned())
+        .spawn(move || {
+            if rusb::has_hotplug() {
+                if let Err(e) = watcher.watch() {
+                    warn!("Hotplug events failed: {}", e);
+                }
+            }
+            watcher.poll();
+        });
+    if let Err(e) = result {
+        error!("Failed to start the device watcher: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn known(devices: &[(u16, &str)]) -> HashMap<u16, String> {
+        devices
+            .iter()
+            .map(|&(address, sn)| (address, sn.to_owned()))
+            .collect()
+    }
+
+    fn event(address: u16, serial_number: &str) -> DeviceEvent {
+        DeviceEvent {
+            address,
+            serial_number: serial_number.to_owned(),
+        }
+    }
+
+    #[test]
+    fn arrivals_and_removals_are_reported_once() {
+        let mut serial_numbers = HashMap::new();
+        assert_eq!(
+            arrived(&mut serial_numbers, 0x101, "A".to_owned()),
+            Some(event(0x101, "A"))
+        );
+        assert_eq!(arrived(&mut serial_numbers, 0x101, "A".to_owned()), None);
+        assert_eq!(removed(&mut serial_numbers, 0x101), Some(event(0x101, "A")));
+        assert_eq!(removed(&mut serial_numbers, 0x101), None);
+        assert!(serial_numbers.is_empty());
+    }
+
+    #[test]
+    fn reconcile_only_reads_new_devices() {
+        let mut serial_numbers = known(&[(0x101, "A"), (0x102, "B")]);
+        let mut reads = Vec::new();
+        let (arrivals, removals) = reconcile(&mut serial_numbers, &[0x101, 0x103, 0x104], |a| {
+            reads.push(a);
+            // The device at 0x104 couldn't be opened
+            (a == 0x103).then(|| "C".to_owned())
+        });
+
+        assert_eq!(reads, vec![0x103, 0x104]);
+        assert_eq!(arrivals, vec![event(0x103, "C")]);
+        assert_eq!(removals, vec![event(0x102, "B")]);
+        assert_eq!(serial_numbers, known(&[(0x101, "A"), (0x103, "C")]));
+    }
+}
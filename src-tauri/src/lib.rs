@@ -1,8 +1,9 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
-use byteorder::{LittleEndian, ReadBytesExt};
+use capabilities::{Capabilities, FirmwareCapabilities, DEFAULT_SAMPLE_RATE};
 use commands::Command;
 use commands::FactoryReset;
+use commands::GetActiveConfiguration;
 use commands::GetStoredConfiguration;
 use commands::GetVersion;
 use commands::SaveConfiguration;
@@ -11,20 +12,21 @@ use commands::SetFilterConfiguration;
 use commands::SetPcm3060Configuration;
 use commands::SetPreprocessingConfiguration;
 use commands::StructureTypes;
-use filters::Filters;
-use low_level::read_filter;
-use parking_lot::Mutex;
-use rusb::{Device, DeviceHandle, Direction, UsbContext};
+use error::Error;
+use filters::{CoefficientSet, CustomIIRFilter, Filters};
+use hotplug::InaccessibleDevice;
+use registry::{DeviceModel, Quirk, Registry};
+use response::{BiquadAnalysis, FiltersResponse, Headroom};
+use rusb::{Device, Direction, UsbContext};
 use serde::{Deserialize, Serialize};
+use simulator::{SimulatedDevice, SIMULATOR_SERIAL_NUMBER};
 use std::collections::HashMap;
 use std::default::Default;
-use std::io::BufRead;
-use std::io::Cursor;
-use std::io::Seek;
-use std::io::SeekFrom;
-use std::str;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tauri::State;
+use tlv::{TlvReader, TlvStructure};
+use transport::{read_ok_response, write_request, RusbTransport, TransferMode, Transport};
+use worker::{UsbWorker, COMMAND_TIMEOUT, FLASH_TIMEOUT};
 // Window shadow support
 use tauri::Manager;
 
@@ -35,51 +37,228 @@ use simplelog::*;
 use std::fs;
 use std::fs::File;
 
+mod capabilities;
 mod commands;
+mod error;
 mod filters;
+#[cfg(fuzzing)]
+pub mod fuzzing;
+mod hotplug;
 mod low_level;
+mod registry;
+mod response;
+mod simulator;
+mod tlv;
+mod transport;
+mod verify;
+mod worker;
 
 pub const LIBUSB_RECIPIENT_DEVICE: u8 = 0x00;
 pub const LIBUSB_REQUEST_TYPE_VENDOR: u8 = 0x02 << 5;
 pub const USB_TIMEOUT: Duration = Duration::from_millis(250);
+/// The shortest gap between configuration writes, so live tuning doesn't flood the device.
+pub const DEFAULT_WRITE_INTERVAL: Duration = Duration::from_millis(30);
+/// The longest `set_write_interval` accepts, held back writes must still finish well within
+/// `COMMAND_TIMEOUT`.
+const MAX_WRITE_INTERVAL: Duration = Duration::from_secs(1);
 const MAX_CFG_LEN: usize = 512;
 
 #[derive(Debug, Default)]
 pub struct ConnectionState {
     serial_numbers: HashMap<u16, String>, // Maps addresses to serial numbers
-    connected: Option<ConnectedDevice>,
+    devices: HashMap<String, ConnectedDevice>, // Open devices, keyed by serial number
+    current: Option<String>,              // The device commands go to unless they name another
+    simulator: Option<SimulatedDevice>,
+    verify_writes: bool, // Read back the configuration after writing or saving it
+    context: Option<rusb::Context>, // Shared with the hotplug thread
+    write_interval: Duration, // Minimum time between configuration writes
+    sessions: HashMap<String, Session>, // Keyed by serial number
+    restore_config: bool, // Re-apply the last written configuration after reconnecting
+    registry: Registry,  // The kinds of device we can configure
+    device_names: HashMap<String, String>, // Display names of the models, keyed by serial number
+    inaccessible: HashMap<u16, InaccessibleDevice>, // Devices we lack permission to open
+    auto_preamp: Option<f32>, // The safety margin in dB, if the preamp is calculated on writes
+}
+
+/// Kept for each device the user opened, so it can be reopened if it drops off the bus.
+#[derive(Debug, Default)]
+struct Session {
+    last_config: Option<Config>, // The last configuration written in this session
 }
 
 impl ConnectionState {
-    fn check_connection(&mut self) -> bool {
-        let handle = match &self.connected {
-            Some(x) => x,
-            None => return false,
+    fn new() -> Self {
+        let simulator = SimulatedDevice::from_env();
+        if simulator.is_some() {
+            info!("Device simulator enabled");
+        }
+        Self {
+            simulator,
+            write_interval: DEFAULT_WRITE_INTERVAL,
+            ..Default::default()
+        }
+    }
+
+    /// A connection to a single device, which commands go to.
+    #[cfg(test)]
+    fn connected_to(transport: impl Transport + 'static) -> Self {
+        let mut connection = Self::default();
+        connection
+            .devices
+            .insert("test".to_owned(), ConnectedDevice::new(transport));
+        connection.current = Some("test".to_owned());
+        connection
+    }
+
+    fn device_list(&self) -> Vec<String> {
+        let mut device_list: Vec<String> = self.serial_numbers.values().cloned().collect();
+        if self.simulator.is_some() {
+            device_list.push(SIMULATOR_SERIAL_NUMBER.to_owned());
+        }
+        device_list
+    }
+
+    fn usb_context(&self) -> Result<rusb::Context, Error> {
+        match &self.context {
+            Some(context) => Ok(context.clone()),
+            None => {
+                rusb::Context::new().map_err(|e| Error::usb("Can't create a libusb context", e))
+            }
+        }
+    }
+
+    fn device(&self) -> Result<&ConnectedDevice, Error> {
+        self.current
+            .as_ref()
+            .and_then(|sn| self.devices.get(sn))
+            .ok_or(Error::NotConnected)
+    }
+
+    fn device_mut(&mut self) -> Result<&mut ConnectedDevice, Error> {
+        self.current
+            .as_ref()
+            .and_then(|sn| self.devices.get_mut(sn))
+            .ok_or(Error::NotConnected)
+    }
+
+    /// Runs `f` against the named device, or the current one if no device is named.
+    fn with_device<R>(
+        &mut self,
+        serial_number: Option<&str>,
+        f: impl FnOnce(&mut Self) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        let serial_number = match serial_number {
+            Some(x) => x.to_owned(),
+            None => return f(self),
         };
+        let current = self.current.replace(serial_number);
+        let result = f(self);
+        self.current = current;
+        result
+    }
 
-        if !handle.is_connected() {
-            self.connected = None;
+    fn check_connection(&mut self) -> bool {
+        match self.current.clone() {
+            Some(serial_number) => self.check_device(&serial_number),
+            None => false,
+        }
+    }
+
+    /// Checks every device the user opened, reconnecting any which have come back.
+    fn check_connections(&mut self) {
+        let serial_numbers: Vec<String> = self.sessions.keys().cloned().collect();
+        for serial_number in serial_numbers {
+            self.check_device(&serial_number);
+        }
+    }
+
+    /// Checks the device is still there, reconnecting to it if it has been unplugged and plugged
+    /// back in, or reset.
+    fn check_device(&mut self, serial_number: &str) -> bool {
+        if let Some(handle) = self.devices.get(serial_number) {
+            if handle.is_connected() {
+                return true;
+            }
+            info!("Lost the connection to {}", serial_number);
+            self.devices.remove(serial_number);
+        }
+        self.reconnect(serial_number)
+    }
+
+    fn reconnect(&mut self, serial_number: &str) -> bool {
+        if !self.sessions.contains_key(serial_number) {
+            return false;
+        }
+        // Wait until the hotplug thread has seen the device come back
+        if serial_number != SIMULATOR_SERIAL_NUMBER
+            && !self.serial_numbers.values().any(|sn| sn == serial_number)
+        {
             return false;
         }
 
+        if let Err(e) = self.connect(serial_number) {
+            warn!("Failed to reconnect to {}: {}", serial_number, e);
+            return false;
+        }
+        info!("Reconnected to {}", serial_number);
+        if self.restore_config {
+            if let Err(e) = self.with_device(Some(serial_number), Self::restore_last_config) {
+                warn!("Failed to restore the configuration: {}", e);
+            }
+        }
         true
     }
+
+    /// Re-applies the last configuration written in this session if the device came back running
+    /// its stored configuration, i.e. it lost our changes when it was reset. A device which kept
+    /// its active configuration is left alone.
+    fn restore_last_config(&mut self) -> Result<(), Error> {
+        let session = self.current.as_ref().and_then(|sn| self.sessions.get(sn));
+        let config = match session.and_then(|s| s.last_config.clone()) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let active = self.load_active_config()?;
+        let stored = self.load_config()?;
+        if active.diff(&stored) == ConfigDiff::default()
+            && active.diff(&config) != ConfigDiff::default()
+        {
+            info!("Re-applying the configuration written before the device was reset");
+            self.write_config(&config)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub struct ConnectedDevice {
-    device_handle: DeviceHandle<rusb::Context>,
-    configuration_interface: ConfigurationInterface,
+    transport: Box<dyn Transport>,
+    transfer_mode: TransferMode,
+    capabilities: Capabilities,
+    last_configuration: Option<Vec<u8>>, // The last SetConfiguration the device acknowledged
+    last_write: Option<Instant>,
+    model: Option<DeviceModel>, // The registry entry the device was opened as
 }
 
 impl ConnectedDevice {
+    fn new(transport: impl Transport + 'static) -> Self {
+        Self {
+            transport: Box::new(transport),
+            transfer_mode: TransferMode::default(),
+            capabilities: Capabilities::default(),
+            last_configuration: None,
+            last_write: None,
+            model: None,
+        }
+    }
+
     fn is_connected(&self) -> bool {
-        self.device_handle.active_configuration().is_ok()
+        self.transport.is_connected()
     }
 }
 
 #[derive(Debug)]
-struct ConfigurationInterface {
+pub struct ConfigurationInterface {
     interface: u8,
     input: u8,
     output: u8,
@@ -89,10 +268,30 @@ struct ConfigurationInterface {
 struct PollDeviceStatus {
     connected: bool,
     device_list: Vec<String>,
+    open_devices: Vec<String>,
+    device_names: HashMap<String, String>,
+    inaccessible: Vec<InaccessibleDevice>,
+}
+
+/// What the frontend should know about a configuration which was written.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+struct WriteOutcome {
+    /// The preamp in dB which was written in place of the configured one, with `auto_preamp`.
+    preamp: Option<f32>,
+    /// Filters which can't work at some of the sample rates the device plays.
+    warnings: Vec<String>,
+}
+
+/// The outcome of writing a configuration to one of several devices.
+#[derive(Serialize, Debug)]
+struct BroadcastResult {
+    serial_number: String,
+    error: Option<Error>,
 }
 
 fn find_configuration_endpoints<T: UsbContext>(
     device: &Device<T>,
+    interface_class: u8,
 ) -> Option<ConfigurationInterface> {
     let device_desc = device.device_descriptor().ok()?;
     for n in 0..device_desc.num_configurations() {
@@ -103,7 +302,7 @@ fn find_configuration_endpoints<T: UsbContext>(
 
         for interface in config_desc.interfaces() {
             for interface_desc in interface.descriptors() {
-                if interface_desc.class_code() != 0xff {
+                if interface_desc.class_code() != interface_class {
                     continue;
                 }
 
@@ -131,7 +330,7 @@ fn find_configuration_endpoints<T: UsbContext>(
     None
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct Preprocessing {
     preamp: f32,
     post_eq_gain: f32,
@@ -146,26 +345,35 @@ impl Preprocessing {
             reverse_stereo,
         }
     }
+}
+
+impl TlvStructure for Preprocessing {
+    const TYPE: u16 = StructureTypes::PreProcessingConfiguration as u16;
 
-    fn to_payload(&self) -> Vec<u8> {
-        let mut preprocessing_payload: Vec<u8> = Vec::new();
+    fn encode_value(&self, buf: &mut Vec<u8>) {
         // TODO: -1.0 as the firmware adds 1, cleanup later. Consider storing this value without the subtraction
         // to eliminate a math op and make the code more grokable?
-        //preprocessing_payload.extend_from_slice(&(f32::powf(10.0, cfg.preprocessing.preamp/20.0) - 1.0).to_le_bytes());
-        preprocessing_payload
-            .extend_from_slice(&(f32::powf(10.0, self.preamp / 20.0) - 1.0).to_le_bytes());
+        buf.extend_from_slice(&(f32::powf(10.0, self.preamp / 20.0) - 1.0).to_le_bytes());
 
         /* Send the post-EQ gain value from the UI. */
-        preprocessing_payload
-            .extend_from_slice(&(f32::powf(10.0, self.post_eq_gain / 20.0) - 1.0).to_le_bytes());
+        buf.extend_from_slice(&(f32::powf(10.0, self.post_eq_gain / 20.0) - 1.0).to_le_bytes());
 
-        preprocessing_payload.push(self.reverse_stereo as u8);
-        preprocessing_payload.extend_from_slice(&[0u8; 3]);
-        preprocessing_payload
+        buf.push(self.reverse_stereo as u8);
+        buf.extend_from_slice(&[0u8; 3]); // reserved bytes
+    }
+
+    fn decode_value(cur: &mut TlvReader) -> Result<Self, Error> {
+        cur.expect_len(12)?;
+        // +1 to maintain compatability with old firmwares
+        let preamp = cur.read_f32()? + 1.0;
+        let post_eq_gain = cur.read_f32()? + 1.0;
+        let reverse_stereo = cur.read_bool()?;
+        cur.skip(3)?; // reserved bytes
+        Ok(Preprocessing::new(preamp, post_eq_gain, reverse_stereo))
     }
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct Codec {
     oversampling: bool,
     phase: bool,
@@ -182,18 +390,31 @@ impl Codec {
             de_emphasis,
         }
     }
+}
+
+impl TlvStructure for Codec {
+    const TYPE: u16 = StructureTypes::Pcm3060Configuration as u16;
 
-    fn to_payload(&self) -> Vec<u8> {
-        vec![
+    fn encode_value(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&[
             self.oversampling as u8,
             self.phase as u8,
             self.rolloff as u8,
             self.de_emphasis as u8,
-        ]
+        ]);
+    }
+
+    fn decode_value(cur: &mut TlvReader) -> Result<Self, Error> {
+        cur.expect_len(4)?;
+        let oversampling = cur.read_bool()?;
+        let phase = cur.read_bool()?;
+        let rolloff = cur.read_bool()?;
+        let de_emphasis = cur.read_bool()?;
+        Ok(Codec::new(oversampling, phase, rolloff, de_emphasis))
     }
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct Config {
     pub preprocessing: Preprocessing,
     pub filters: Filters,
@@ -208,6 +429,39 @@ impl Config {
             codec,
         }
     }
+
+    /// Compares each configuration structure by its encoding, so values that only differ by
+    /// rounding in the conversion to the wire format are treated as equal.
+    pub fn diff(&self, other: &Config) -> ConfigDiff {
+        fn differs<T: TlvStructure>(a: &T, b: &T) -> bool {
+            let (mut a_buf, mut b_buf) = (Vec::new(), Vec::new());
+            a.encode_value(&mut a_buf);
+            b.encode_value(&mut b_buf);
+            a_buf != b_buf
+        }
+
+        ConfigDiff {
+            preprocessing: differs(&self.preprocessing, &other.preprocessing),
+            filters: differs(&self.filters, &other.filters),
+            codec: differs(&self.codec, &other.codec),
+        }
+    }
+
+    /// Appends the configuration structures, this is the value of SetConfiguration.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        self.preprocessing.encode(buf);
+        self.filters.encode(buf);
+        self.codec.encode(buf);
+    }
+}
+
+/// Which parts of one configuration differ from another, e.g. the active configuration on the
+/// device compared with the one stored in flash.
+#[derive(Serialize, Default, Debug, PartialEq)]
+pub struct ConfigDiff {
+    pub preprocessing: bool,
+    pub filters: bool,
+    pub codec: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -216,336 +470,689 @@ struct VersionInfo {
     minimum_supported_version: u16,
     git_hash: String,
     pico_sdk_version: String,
+    capabilities: Option<FirmwareCapabilities>,
 }
 
 impl VersionInfo {
-    fn from_buf(buf: &[u8]) -> Result<Self, String> {
-        let mut cur = Cursor::new(buf);
-        let _result_type_val = cur.read_u16::<LittleEndian>().unwrap();
-        let _result_length_val = cur.read_u16::<LittleEndian>().unwrap();
-
-        let _version_tlv_type_val = cur.read_u16::<LittleEndian>().unwrap();
-        let _version_tlv_length_val = cur.read_u16::<LittleEndian>().unwrap();
-
-        let current_version = cur.read_u16::<LittleEndian>().unwrap();
-        let minimum_supported_version = cur.read_u16::<LittleEndian>().unwrap();
-        cur.consume(4);
-        let mut str_buf: Vec<u8> = Vec::new();
-        cur.read_until(0u8, &mut str_buf).unwrap();
-        str_buf.pop();
-        let git_hash = match str::from_utf8(&str_buf) {
-            Ok(s) => s.to_string(),
-            Err(e) => return Err(format!("Invalid UTF-8 sequence: {}", e)),
-        };
-        str_buf.clear();
-        cur.read_until(0u8, &mut str_buf).unwrap();
-        str_buf.pop();
-        let pico_sdk_version = match str::from_utf8(&str_buf) {
-            Ok(s) => s.to_string(),
-            Err(e) => return Err(format!("Invalid UTF-8 sequence: {}", e)),
-        };
+    fn from_buf(buf: &[u8]) -> Result<Self, Error> {
+        let (_, mut body) = TlvReader::new(buf).read_tlv()?;
+        Self::decode(&mut body)
+    }
+}
+
+impl TlvStructure for VersionInfo {
+    const TYPE: u16 = StructureTypes::VersionStatus as u16;
+
+    fn encode_value(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.current_version.to_le_bytes());
+        buf.extend_from_slice(&self.minimum_supported_version.to_le_bytes());
+        buf.extend_from_slice(&[0u8; 4]); // reserved bytes
+        for s in [&self.git_hash, &self.pico_sdk_version] {
+            buf.extend_from_slice(s.as_bytes());
+            buf.push(0);
+        }
+        if let Some(capabilities) = &self.capabilities {
+            capabilities.encode(buf);
+        }
+    }
+
+    fn decode_value(cur: &mut TlvReader) -> Result<Self, Error> {
+        let current_version = cur.read_u16()?;
+        let minimum_supported_version = cur.read_u16()?;
+        cur.skip(4)?; // reserved bytes
+        let git_hash = cur.read_c_string()?;
+        let pico_sdk_version = cur.read_c_string()?;
+
+        // Anything after the strings is optional, firmwares may pad the structure, so parsing
+        // stops at the first thing that isn't a TLV.
+        let mut capabilities = None;
+        while let Ok((type_val, mut value)) = cur.read_tlv() {
+            if type_val == FirmwareCapabilities::TYPE {
+                match FirmwareCapabilities::decode_value(&mut value) {
+                    Ok(x) => capabilities = Some(x),
+                    Err(e) => warn!("Ignoring malformed firmware capabilities: {}", e),
+                }
+            }
+        }
 
         Ok(Self {
             current_version,
             minimum_supported_version,
             git_hash,
             pico_sdk_version,
+            capabilities,
         })
     }
 }
 
-fn send_cmd(
-    connection_state: State<'_, Mutex<ConnectionState>>,
-    cmd: impl Command,
-) -> Result<[u8; MAX_CFG_LEN], String> {
-    let mut buf = Vec::new();
-    cmd.write_as_binary(&mut buf);
-    let connection = connection_state.lock();
-
-    let device = match &connection.connected {
-        Some(x) => x,
-        None => {
-            info!("The device is not connected.");
-            return Err("Not connected".to_owned());
+/// Parses a GetStoredConfiguration or GetActiveConfiguration response. Malformed responses are reported as errors.
+fn parse_config(buf: &[u8]) -> Result<Config, Error> {
+    let (_, mut body) = TlvReader::new(buf).read_tlv()?;
+    let mut cfg = Config::default();
+    while !body.is_empty() {
+        let (type_val, mut cur) = body.read_tlv()?;
+        match type_val {
+            Preprocessing::TYPE => cfg.preprocessing = Preprocessing::decode_value(&mut cur)?,
+            Filters::TYPE => cfg.filters = Filters::decode_value(&mut cur)?,
+            Codec::TYPE => cfg.codec = Codec::decode_value(&mut cur)?,
+            _ => {
+                warn!("Unsupported TLV type {}", type_val);
+            }
         }
-    };
+    }
+    Ok(cfg)
+}
 
-    let interface = &device.configuration_interface;
+impl ConnectionState {
+    fn send_cmd(&mut self, cmd: impl Command) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::new();
+        cmd.write_as_binary(&mut buf);
+        self.send_buf(&buf)
+    }
 
-    //println!("Write {} bytes to {}", buf.len(), interface.output);
-    match device
-        .device_handle
-        .write_bulk(interface.output, &buf, USB_TIMEOUT)
-    {
-        Ok(_) => (),
-        Err(err) => {
-            return Err(format!(
-                "Failed to write to the configuration interface: {}",
-                err
-            ));
+    fn send_buf(&mut self, buf: &[u8]) -> Result<Vec<u8>, Error> {
+        let device = match self.device_mut() {
+            Ok(x) => x,
+            Err(e) => {
+                info!("The device is not connected.");
+                return Err(e);
+            }
+        };
+
+        //println!("Write {} bytes", buf.len());
+        write_request(device.transport.as_mut(), buf, device.transfer_mode)?;
+        read_ok_response(device.transport.as_mut()).inspect_err(|e| {
+            if let Error::Nok { .. } = e {
+                warn!("{}", e);
+            }
+        })
+    }
+
+    /// Asks the newly connected device which protocol versions and transfer modes it supports,
+    /// failing if it can't be used with this client. Firmwares which predate chunked transfers
+    /// keep using single frames.
+    fn negotiate(&mut self) -> Result<(), Error> {
+        let buf = self.send_cmd(GetVersion::new())?;
+        let capabilities = Capabilities::negotiate(&VersionInfo::from_buf(&buf)?)?;
+        let mode = TransferMode::from_version_response(&buf);
+        if let Ok(device) = self.device_mut() {
+            device.transfer_mode = mode;
+            device.capabilities = capabilities;
+            if let Some(model) = &device.model {
+                model.apply(&mut device.transfer_mode, &mut device.capabilities);
+            }
+            info!(
+                "Using transfer mode {:?}, {:?}",
+                device.transfer_mode, device.capabilities
+            );
+        }
+        Ok(())
+    }
+
+    /// Negotiates with a device which was just connected, disconnecting it if it is unusable.
+    fn open_negotiated(&mut self) -> Result<(), Error> {
+        let result = self.negotiate();
+        if let Err(e) = &result {
+            error!("Can't use the device: {}", e);
+            if let Some(serial_number) = &self.current {
+                self.devices.remove(serial_number);
+            }
         }
+        result
     }
 
-    let mut result = [0; MAX_CFG_LEN];
-    let mut read_length: u16 = 0;
-    let mut length: u16 = 4;
-    while read_length < length {
-        match device
-            .device_handle
-            .read_bulk(interface.input, &mut result, USB_TIMEOUT)
+    fn capabilities(&self) -> Result<Capabilities, Error> {
+        Ok(self.device()?.capabilities.clone())
+    }
+
+    fn write_config(&mut self, config: &Config) -> Result<WriteOutcome, Error> {
+        let capabilities = self.capabilities()?;
+        let mut config = config.clone();
+        let mut preamp = None;
+        if let Some(safety_margin) = self.auto_preamp {
+            let headroom = response::headroom(
+                &config.filters,
+                f64::from(config.preprocessing.post_eq_gain),
+                f64::from(safety_margin),
+                &capabilities.sample_rates,
+            );
+            info!("Setting the preamp automatically, {:?}", headroom);
+            config.preprocessing.preamp = headroom.preamp_db as f32;
+            preamp = Some(config.preprocessing.preamp);
+        }
+        let config = &config;
+        let outcome = WriteOutcome {
+            preamp,
+            warnings: config.filters.nyquist_warnings(&capabilities.sample_rates),
+        };
+        for warning in &outcome.warnings {
+            warn!("{}", warning);
+        }
+        let prep = SetPreprocessingConfiguration::new(&config.preprocessing);
+        let filters = SetFilterConfiguration::new(&config.filters, &capabilities)?;
+        let codec = SetPcm3060Configuration::new(&config.codec);
+        let mut cmd = SetConfiguration::new(prep, filters, codec);
+        // Boards without a codec are narrowed out by the registry in `negotiate`
+        if !capabilities.supports(StructureTypes::Pcm3060Configuration) {
+            info!("This device doesn't take codec settings, leaving them out");
+            cmd = cmd.without_codec();
+        }
+        let mut buf = Vec::new();
+        cmd.write_as_binary(&mut buf);
+
+        let device = self.device_mut()?;
+        if device.last_configuration.as_ref() == Some(&buf) {
+            info!("Skipping a configuration write, the device already has it");
+            return Ok(outcome);
+        }
+        // Forget the last write until this one is acknowledged, we don't know what a failed
+        // write left behind.
+        device.last_configuration = None;
+        device.last_write = Some(Instant::now());
+        self.send_buf(&buf)?;
+        if let Some(session) = self
+            .current
+            .as_ref()
+            .and_then(|sn| self.sessions.get_mut(sn))
         {
-            Ok(len) => {
-                //println!("Read {} {}/{}", len, read_length, length);
-                if read_length < 4 && len >= 4 {
-                    let length_bytes: [u8; 2] = result[2..4].try_into().unwrap();
-                    length = u16::from_le_bytes(length_bytes);
-                    //println!("Length: {}", length);
-                    if usize::from(length) > MAX_CFG_LEN {
-                        return Err(format!("Overflow reading from the config interface, got {} bytes, max size is {} bytes.", length, MAX_CFG_LEN));
-                    }
+            session.last_config = Some(config.clone());
+        }
+
+        if self.verify_writes {
+            let applied = self.load_active_config()?;
+            verify::check(config, &applied, &capabilities)?;
+        }
+        // Only now do we know the device has it, a retry after a failed check must send it again
+        if let Ok(device) = self.device_mut() {
+            device.last_configuration = Some(buf);
+        }
+        Ok(outcome)
+    }
+
+    fn save_config(&mut self) -> Result<(), Error> {
+        self.send_cmd(SaveConfiguration::new())?;
+        if self.verify_writes {
+            let active = self.load_active_config()?;
+            let stored = self.load_config()?;
+            verify::check(&active, &stored, &self.capabilities()?)?;
+        }
+        Ok(())
+    }
+
+    fn load_config(&mut self) -> Result<Config, Error> {
+        let cfg = self.send_cmd(GetStoredConfiguration::new())?;
+        parse_config(&cfg)
+    }
+
+    fn load_active_config(&mut self) -> Result<Config, Error> {
+        let cfg = self.send_cmd(GetActiveConfiguration::new())?;
+        parse_config(&cfg)
+    }
+
+    fn config_diff(&mut self) -> Result<ConfigDiff, Error> {
+        let active = self.load_active_config()?;
+        let stored = self.load_config()?;
+        Ok(active.diff(&stored))
+    }
+
+    /// How much longer the next configuration write to a device has to wait to respect
+    /// `write_interval`.
+    fn write_delay(&self, serial_number: &str) -> Duration {
+        match self.devices.get(serial_number).and_then(|d| d.last_write) {
+            Some(last_write) => self.write_interval.saturating_sub(last_write.elapsed()),
+            None => Duration::ZERO,
+        }
+    }
+
+    fn factory_reset(&mut self) -> Result<(), Error> {
+        if let Ok(device) = self.device_mut() {
+            device.last_configuration = None;
+        }
+        self.send_cmd(FactoryReset::new())?;
+        Ok(())
+    }
+
+    fn reboot_bootloader(&mut self) -> Result<(), Error> {
+        let device = self.device_mut()?;
+        if let Some(model) = &device.model {
+            if model.has_quirk(Quirk::NoBootloaderReboot) {
+                return Err(Error::Validation(format!(
+                    "{} can't be rebooted into its bootloader over USB",
+                    model.name
+                )));
+            }
+        }
+        let r = device.transport.write_control(
+            LIBUSB_RECIPIENT_DEVICE | LIBUSB_REQUEST_TYPE_VENDOR,
+            0,
+            0x2e8a,
+            0,
+            &[],
+        );
+        info!("Reboot Device: {}", r.is_err());
+
+        Ok(())
+    }
+
+    fn read_version_info(&mut self) -> Result<VersionInfo, Error> {
+        let v = self.send_cmd(GetVersion::new())?;
+        let version = VersionInfo::from_buf(&v)?;
+        Ok(version)
+    }
+
+    /// Writes the same configuration to several devices, reporting how each one went.
+    fn broadcast_config(
+        &mut self,
+        serial_numbers: &[String],
+        config: &Config,
+    ) -> Vec<BroadcastResult> {
+        serial_numbers
+            .iter()
+            .map(|serial_number| BroadcastResult {
+                serial_number: serial_number.clone(),
+                error: self
+                    .with_device(Some(serial_number), |c| c.write_config(config))
+                    .err(),
+            })
+            .collect()
+    }
+
+    /// Opens a device and makes it the one commands go to. Other open devices stay open, and a
+    /// device which is reopened keeps its session.
+    fn open(&mut self, serial_number: &str) -> Result<(), Error> {
+        self.connect(serial_number)?;
+        self.current = Some(serial_number.to_owned());
+        self.sessions.entry(serial_number.to_owned()).or_default();
+        Ok(())
+    }
+
+    fn close(&mut self, serial_number: &str) {
+        self.devices.remove(serial_number);
+        self.sessions.remove(serial_number);
+        if self.current.as_deref() == Some(serial_number) {
+            self.current = None;
+        }
+    }
+
+    fn connect(&mut self, serial_number: &str) -> Result<(), Error> {
+        self.devices.remove(serial_number);
+
+        if serial_number == SIMULATOR_SERIAL_NUMBER {
+            if let Some(simulator) = &self.simulator {
+                if !simulator.is_connected() {
+                    return Err(Error::DeviceNotFound {
+                        serial_number: serial_number.to_owned(),
+                    });
                 }
-                read_length += len as u16;
+                info!("Opened the simulated device");
+                let device = ConnectedDevice::new(simulator.clone());
+                self.devices.insert(serial_number.to_owned(), device);
+                return self.with_device(Some(serial_number), Self::open_negotiated);
             }
-            Err(err) => {
-                return Err(format!(
-                    "Error reading from the configuration inteface: {}",
-                    err
-                ));
+        }
+
+        let devices = self
+            .usb_context()?
+            .devices()
+            .map_err(|e| Error::usb("Device not found", e))?;
+
+        for device in devices.iter() {
+            let address = hotplug::device_address(&device);
+            let sn = match self.serial_numbers.get(&address) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            if sn != serial_number {
+                continue;
             }
+            let model = device
+                .device_descriptor()
+                .ok()
+                .and_then(|d| self.registry.find(d.vendor_id(), d.product_id()))
+                .cloned();
+            let Some(model) = model else {
+                continue;
+            };
+
+            let handle = device.open().map_err(|e| Error::usb("Could not open", e))?;
+            let interface = find_configuration_endpoints(&device, model.interface_class)
+                .ok_or_else(|| {
+                    Error::Protocol("Could not detect a configuration interface".to_owned())
+                })?;
+            handle
+                .claim_interface(interface.interface)
+                .map_err(|e| Error::usb("Could not claim interface", e))?;
+
+            info!(
+                "Opened the device at address {}, with serial number {}",
+                address, sn
+            );
+            let mut device = ConnectedDevice::new(RusbTransport::new(handle, interface));
+            device.model = Some(model);
+            self.devices.insert(serial_number.to_owned(), device);
+            return self.with_device(Some(serial_number), Self::open_negotiated);
         }
+        Err(Error::DeviceNotFound {
+            serial_number: serial_number.to_owned(),
+        })
     }
-    Ok(result)
 }
 
 #[tauri::command]
-fn write_config(
+async fn write_config(
     config: Config,
-    connection_state: State<'_, Mutex<ConnectionState>>,
-) -> Result<(), String> {
-    let prep = SetPreprocessingConfiguration::new(&config.preprocessing);
-    let filters = SetFilterConfiguration::new(&config.filters)?;
-    let codec = SetPcm3060Configuration::new(&config.codec);
-    let cmd = SetConfiguration::new(prep, filters, codec);
-    send_cmd(connection_state, cmd)?;
-    Ok(())
+    serial_number: String,
+    worker: State<'_, UsbWorker>,
+) -> Result<WriteOutcome, Error> {
+    worker.write_config(serial_number, config).await
 }
 
+/// Writes the same configuration to several devices, reporting the outcome for each of them.
 #[tauri::command]
-fn save_config(connection_state: State<'_, Mutex<ConnectionState>>) -> Result<(), String> {
-    send_cmd(connection_state, SaveConfiguration::new())?;
-    Ok(())
+async fn broadcast_config(
+    config: Config,
+    serial_numbers: Vec<String>,
+    worker: State<'_, UsbWorker>,
+) -> Result<Vec<BroadcastResult>, Error> {
+    let timeout = COMMAND_TIMEOUT * serial_numbers.len().max(1) as u32;
+    worker
+        .run("broadcast_config", timeout, move |c| {
+            Ok(c.broadcast_config(&serial_numbers, &config))
+        })
+        .await
 }
 
+/// When enabled, write_config and save_config read the configuration back and fail if the device
+/// didn't apply what was sent.
 #[tauri::command]
-fn load_config(connection_state: State<'_, Mutex<ConnectionState>>) -> Result<Config, String> {
-    let binding = send_cmd(connection_state, GetStoredConfiguration::new());
-    let cfg = match &binding {
-        Ok(x) => x,
-        Err(e) => {
-            // TODO: Check for NOK
-            return Err(format!("Error reading config: {}", e));
-        }
-    };
-
-    let mut cur = Cursor::new(cfg);
-    let _result_type_val = cur.read_u16::<LittleEndian>().unwrap();
-    let result_length_val = cur.read_u16::<LittleEndian>().unwrap();
-    let mut position = 4;
-    let mut cfg = Config::default();
-    while position < result_length_val {
-        let type_val = cur.read_u16::<LittleEndian>().unwrap();
-        let length_val = cur.read_u16::<LittleEndian>().unwrap();
-        match type_val {
-            x if x == StructureTypes::PreProcessingConfiguration as u16 => {
-                // +1 to maintain compatability with old firmwares
-                let preamp = cur.read_f32::<LittleEndian>().unwrap() + 1.0;
-                let post_eq_gain = cur.read_f32::<LittleEndian>().unwrap() + 1.0;
-                let reverse_stereo = cur.read_u8().unwrap() != 0;
-
-                cfg.preprocessing = Preprocessing::new(preamp, post_eq_gain, reverse_stereo);
-                let _ = cur.seek(SeekFrom::Current(3)); // reserved bytes
-            }
-            x if x == StructureTypes::FilterConfiguration as u16 => {
-                let end = cur.position() + (length_val - 4) as u64;
-                while cur.position() < end {
-                    cfg.filters.add(read_filter(&mut cur)?, true)
-                }
+async fn set_verify_writes(enabled: bool, worker: State<'_, UsbWorker>) -> Result<(), Error> {
+    worker
+        .run("set_verify_writes", COMMAND_TIMEOUT, move |c| {
+            c.verify_writes = enabled;
+            Ok(())
+        })
+        .await
+}
 
-                if cur.position() != end {
-                    return Err("Read off the end of the filters TLV".to_owned());
-                }
-            }
-            x if x == StructureTypes::Pcm3060Configuration as u16 => {
-                let oversampling = cur.read_u8().unwrap() != 0;
-                let phase = cur.read_u8().unwrap() != 0;
-                let rolloff = cur.read_u8().unwrap() != 0;
-                let de_emphasis = cur.read_u8().unwrap() != 0;
-                cfg.codec = Codec::new(oversampling, phase, rolloff, de_emphasis);
-            }
-            _ => {
-                warn!("Unsupported TLV type {}", type_val);
-            }
-        }
-        //println!("\tT: {} L: {}", type_val, length_val);
-        position += length_val;
-        cur.set_position(position as u64);
-    }
-    Ok(cfg.into())
+/// Sets the minimum time between configuration writes, writes made sooner are held back and
+/// replaced by any newer ones. Intervals over a second are cut to a second.
+#[tauri::command]
+async fn set_write_interval(interval_ms: u64, worker: State<'_, UsbWorker>) -> Result<(), Error> {
+    worker
+        .run("set_write_interval", COMMAND_TIMEOUT, move |c| {
+            c.write_interval = Duration::from_millis(interval_ms).min(MAX_WRITE_INTERVAL);
+            Ok(())
+        })
+        .await
 }
 
+/// When enabled, a device which is reset or replugged gets the last configuration written to it
+/// back, instead of falling back to its stored configuration.
 #[tauri::command]
-fn factory_reset(connection_state: State<'_, Mutex<ConnectionState>>) -> Result<(), String> {
-    send_cmd(connection_state, FactoryReset::new())?;
-    Ok(())
+async fn set_restore_config(enabled: bool, worker: State<'_, UsbWorker>) -> Result<(), Error> {
+    worker
+        .run("set_restore_config", COMMAND_TIMEOUT, move |c| {
+            c.restore_config = enabled;
+            Ok(())
+        })
+        .await
 }
 
 #[tauri::command]
-fn reboot_bootloader(connection_state: State<Mutex<ConnectionState>>) -> Result<(), String> {
-    let connection = connection_state.lock();
-    let device = match &connection.connected {
-        Some(x) => x,
-        None => return Err("No connection".to_owned()),
-    };
-
-    let r = device.device_handle.write_control(
-        LIBUSB_RECIPIENT_DEVICE | LIBUSB_REQUEST_TYPE_VENDOR,
-        0,
-        0x2e8a,
-        0,
-        &[],
-        USB_TIMEOUT,
-    );
-    info!("Reboot Device: {}", r.is_err());
-
-    Ok(())
+async fn save_config(
+    serial_number: Option<String>,
+    worker: State<'_, UsbWorker>,
+) -> Result<(), Error> {
+    worker
+        .run_on(
+            "save_config",
+            FLASH_TIMEOUT,
+            serial_number,
+            ConnectionState::save_config,
+        )
+        .await
 }
 
 #[tauri::command]
-fn read_version_info(
-    connection_state: State<'_, Mutex<ConnectionState>>,
-) -> Result<VersionInfo, String> {
-    let v = send_cmd(connection_state, GetVersion::new())?;
-    let version = VersionInfo::from_buf(&v)?;
-    Ok(version)
+async fn load_config(
+    serial_number: Option<String>,
+    worker: State<'_, UsbWorker>,
+) -> Result<Config, Error> {
+    worker
+        .run_on(
+            "load_config",
+            COMMAND_TIMEOUT,
+            serial_number,
+            ConnectionState::load_config,
+        )
+        .await
 }
 
 #[tauri::command]
-fn open(
-    serial_number: &str,
-    connection_state: State<Mutex<ConnectionState>>,
-) -> Result<(), String> {
-    let context = rusb::Context::new().expect("Can't create libusb::Context::new()");
-
-    let devices = context
-        .devices()
-        .map_err(|e| format!("Device not found: {}", e))?;
-
-    let mut connection = connection_state.lock();
-    connection.connected = None;
-    for device in devices.iter() {
-        let address: u16 = ((device.bus_number() as u16) << 8) | (device.address() as u16);
-        let sn = match connection.serial_numbers.get(&address) {
-            Some(x) => x,
-            None => continue,
-        };
+async fn load_active_config(
+    serial_number: Option<String>,
+    worker: State<'_, UsbWorker>,
+) -> Result<Config, Error> {
+    worker
+        .run_on(
+            "load_active_config",
+            COMMAND_TIMEOUT,
+            serial_number,
+            ConnectionState::load_active_config,
+        )
+        .await
+}
 
-        if sn != serial_number {
-            continue;
-        }
+/// Reports unsaved changes, i.e. differences between the active and stored configurations.
+#[tauri::command]
+async fn config_diff(
+    serial_number: Option<String>,
+    worker: State<'_, UsbWorker>,
+) -> Result<ConfigDiff, Error> {
+    worker
+        .run_on(
+            "config_diff",
+            COMMAND_TIMEOUT,
+            serial_number,
+            ConnectionState::config_diff,
+        )
+        .await
+}
 
-        let handle = device.open().map_err(|e| format!("Could not open {}", e))?;
-        let interface = find_configuration_endpoints(&device)
-            .ok_or_else(|| "Could not detect a configuration interface".to_owned())?;
-        handle
-            .claim_interface(interface.interface)
-            .map_err(|e| format!("Could not claim interface: {}", e))?;
+#[tauri::command]
+async fn factory_reset(
+    serial_number: Option<String>,
+    worker: State<'_, UsbWorker>,
+) -> Result<(), Error> {
+    worker
+        .run_on(
+            "factory_reset",
+            FLASH_TIMEOUT,
+            serial_number,
+            ConnectionState::factory_reset,
+        )
+        .await
+}
 
-        info!(
-            "Opened the device at address {}, with serial number {}",
-            address, sn
-        );
-        connection.connected = Some(ConnectedDevice {
-            device_handle: handle,
-            configuration_interface: interface,
-        });
-        return Ok(());
-    }
-    Err("Can't find device".to_owned())
+#[tauri::command]
+async fn reboot_bootloader(
+    serial_number: Option<String>,
+    worker: State<'_, UsbWorker>,
+) -> Result<(), Error> {
+    worker
+        .run_on(
+            "reboot_bootloader",
+            COMMAND_TIMEOUT,
+            serial_number,
+            ConnectionState::reboot_bootloader,
+        )
+        .await
 }
 
 #[tauri::command]
-fn poll_devices(connection_state: State<Mutex<ConnectionState>>) -> PollDeviceStatus {
-    let mut connection = connection_state.lock();
-    let mut known_devices = std::mem::take(&mut connection.serial_numbers);
-
-    let context = rusb::Context::new().expect("Can't create libusb::Context::new()");
-
-    let devices = match context.devices() {
-        Ok(d) => d,
-        Err(_) => {
-            return PollDeviceStatus {
-                connected: false,
-                device_list: Vec::new(),
-            };
-        }
-    };
+async fn read_version_info(
+    serial_number: Option<String>,
+    worker: State<'_, UsbWorker>,
+) -> Result<VersionInfo, Error> {
+    worker
+        .run_on(
+            "read_version_info",
+            COMMAND_TIMEOUT,
+            serial_number,
+            ConnectionState::read_version_info,
+        )
+        .await
+}
 
-    let serial_numbers = &mut connection.serial_numbers;
+#[tauri::command]
+async fn read_capabilities(
+    serial_number: Option<String>,
+    worker: State<'_, UsbWorker>,
+) -> Result<Capabilities, Error> {
+    worker
+        .run_on("read_capabilities", COMMAND_TIMEOUT, serial_number, |c| {
+            c.capabilities()
+        })
+        .await
+}
 
-    for device in devices.iter() {
-        let device_desc = match device.device_descriptor() {
-            Ok(d) => d,
-            Err(_) => continue,
-        };
+#[tauri::command]
+async fn open(serial_number: String, worker: State<'_, UsbWorker>) -> Result<(), Error> {
+    worker
+        .run("open", COMMAND_TIMEOUT, move |c| c.open(&serial_number))
+        .await
+}
 
-        if device_desc.vendor_id() != 0x2e8a || device_desc.product_id() != 0xfedd {
-            continue;
-        }
+/// Closes a device opened with `open`, it won't be reconnected if it comes back.
+#[tauri::command]
+async fn close(serial_number: String, worker: State<'_, UsbWorker>) -> Result<(), Error> {
+    worker
+        .run("close", COMMAND_TIMEOUT, move |c| {
+            c.close(&serial_number);
+            Ok(())
+        })
+        .await
+}
 
-        let address: u16 = ((device.bus_number() as u16) << 8) | (device.address() as u16);
+/// Drops any commands which are still waiting for the device, e.g. stale writes.
+#[tauri::command]
+fn cancel_pending(worker: State<'_, UsbWorker>) {
+    worker.cancel_pending();
+}
 
-        match known_devices.remove(&address) {
-            Some(sn) => {
-                serial_numbers.insert(address, sn);
-                continue;
-            }
-            None => {}
-        }
+/// Reports the devices tracked by the hotplug thread. The frontend calls this once at startup and
+/// again whenever a device arrives or is removed.
+#[tauri::command]
+async fn poll_devices(worker: State<'_, UsbWorker>) -> Result<PollDeviceStatus, Error> {
+    worker
+        .run("poll_devices", COMMAND_TIMEOUT, |c| {
+            Ok(PollDeviceStatus {
+                connected: c.check_connection(),
+                device_list: c.device_list(),
+                open_devices: c.devices.keys().cloned().collect(),
+                device_names: c.device_names.clone(),
+                inaccessible: c.inaccessible.values().cloned().collect(),
+            })
+        })
+        .await
+}
 
-        info!("New device found at address {}", address);
-        // println!("Device {:#x}:{:#x} {:#x} {:#x} {:#x}", device_desc.vendor_id(), device_desc.product_id(), device_desc.class_code(), device.bus_number(), device.address());
+/// Evaluates the magnitude, phase and group delay of each filter, and of the enabled ones
+/// combined, at the given frequencies in Hz. The sample rate defaults to 48kHz.
+#[tauri::command]
+fn filter_response(
+    filters: Filters,
+    frequencies: Vec<f64>,
+    sample_rate: Option<u32>,
+) -> FiltersResponse {
+    let sample_rate = f64::from(sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE));
+    response::filters_response(&filters, &frequencies, sample_rate)
+}
 
-        let handle = match device.open() {
-            Ok(x) => x,
-            Err(e) => {
-                error!("Open failed {}", e);
-                continue;
-            }
-        };
+/// Recommends a preamp which keeps the filters and post-EQ gain from clipping, leaving
+/// `safety_margin_db` of headroom. Without an open device the filters are checked at 48kHz.
+#[tauri::command]
+async fn recommend_preamp(
+    filters: Filters,
+    post_eq_gain: f32,
+    safety_margin_db: Option<f32>,
+    serial_number: Option<String>,
+    worker: State<'_, UsbWorker>,
+) -> Result<Headroom, Error> {
+    let capabilities = worker
+        .run_on("recommend_preamp", COMMAND_TIMEOUT, serial_number, |c| {
+            c.capabilities()
+        })
+        .await
+        .unwrap_or_default();
+    Ok(response::headroom(
+        &filters,
+        f64::from(post_eq_gain),
+        f64::from(safety_margin_db.unwrap_or(0.0)),
+        &capabilities.sample_rates,
+    ))
+}
 
-        let serial_number_string_index = device_desc.serial_number_string_index().unwrap();
+/// When a safety margin is given, every configuration written gets the preamp `recommend_preamp`
+/// would choose in place of its own, and `write_config` returns it. `None` goes back to the
+/// configured preamp.
+#[tauri::command]
+async fn set_auto_preamp(
+    safety_margin_db: Option<f32>,
+    worker: State<'_, UsbWorker>,
+) -> Result<(), Error> {
+    worker
+        .run("set_auto_preamp", COMMAND_TIMEOUT, move |c| {
+            c.auto_preamp = safety_margin_db;
+            Ok(())
+        })
+        .await
+}
 
-        let sn = match handle.read_string_descriptor_ascii(serial_number_string_index) {
-            Ok(x) => x,
-            Err(e) => {
-                error!("Get serial number failed {}", e);
-                continue;
-            }
-        };
+/// Finds the poles, zeros and maximum gain of custom coefficients, so they can be checked before
+/// they are sent to a device.
+#[tauri::command]
+fn analyze_filter(filter: CustomIIRFilter) -> BiquadAnalysis {
+    response::analyze(&filter)
+}
 
-        info!("Device {} has serial number {}", address, sn);
-        serial_numbers.insert(address, sn);
-    }
+/// The coefficients of a set of filters at each sample rate a device plays.
+#[derive(Serialize, Debug)]
+struct FilterCoefficients {
+    coefficient_sets: Vec<CoefficientSet>,
+    /// Filters which can't work at some of the sample rates.
+    warnings: Vec<String>,
+}
 
-    // Handle unplugged devices
-    for (address, sn) in known_devices {
-        info!("The device {} at address {} was disconnected", sn, address);
-    }
+/// Without an open device the coefficients are calculated for the default sample rates.
+#[tauri::command]
+async fn filter_coefficients(
+    filters: Filters,
+    serial_number: Option<String>,
+    worker: State<'_, UsbWorker>,
+) -> Result<FilterCoefficients, Error> {
+    let capabilities = worker
+        .run_on("filter_coefficients", COMMAND_TIMEOUT, serial_number, |c| {
+            c.capabilities()
+        })
+        .await
+        .unwrap_or_default();
+    Ok(FilterCoefficients {
+        coefficient_sets: filters.coefficients(&capabilities.sample_rates),
+        warnings: filters.nyquist_warnings(&capabilities.sample_rates),
+    })
+}
 
-    PollDeviceStatus {
-        connected: connection.check_connection(),
-        device_list: connection.serial_numbers.values().cloned().collect(),
-    }
+/// Generates udev rules which let the user open every registered device without root, for when
+/// `poll_devices` reports inaccessible devices on Linux.
+#[tauri::command]
+async fn udev_rules(worker: State<'_, UsbWorker>) -> Result<String, Error> {
+    worker
+        .run("udev_rules", COMMAND_TIMEOUT, |c| {
+            Ok(c.registry.udev_rules())
+        })
+        .await
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -580,19 +1187,628 @@ pub fn run() {
             let window = app.get_webview_window("main").unwrap();
             let _ = window.set_resizable(true);
             info!("Headphones Toolbox Started");
+            let registry = app
+                .path()
+                .app_config_dir()
+                .map(|dir| Registry::load(&dir))
+                .unwrap_or_default();
+            hotplug::spawn(app.handle().clone(), registry);
             Ok(())
         })
-        .manage(Mutex::new(ConnectionState::default()))
+        .manage(UsbWorker::spawn(ConnectionState::new()))
         .invoke_handler(tauri::generate_handler![
             reboot_bootloader,
             poll_devices,
+            udev_rules,
+            filter_response,
+            filter_coefficients,
+            analyze_filter,
+            recommend_preamp,
+            set_auto_preamp,
+            cancel_pending,
             open,
+            close,
             write_config,
+            broadcast_config,
+            set_verify_writes,
+            set_write_interval,
+            set_restore_config,
             save_config,
             factory_reset,
             load_config,
-            read_version_info
+            load_active_config,
+            config_diff,
+            read_version_info,
+            read_capabilities
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+/// The configuration the tests write, with a peaking filter at each frequency, enabled or not.
+#[cfg(test)]
+fn test_config(filters: impl IntoIterator<Item = (f32, bool)>) -> Config {
+    let mut f = Filters::default();
+    for (f0, enabled) in filters {
+        f.add(
+            filters::PeakingFilter::new(f0, 0.7, 3.0).unwrap().into(),
+            enabled,
+        );
+    }
+    Config::new(
+        Preprocessing::new(0.5, 1.0, true),
+        f,
+        Codec::new(true, false, true, false),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capabilities::{FilterLimits, PROTOCOL_VERSION};
+    use filters::*;
+    use registry::CodecType;
+    use transport::MemoryTransport;
+
+    fn connect(transport: &MemoryTransport) -> ConnectionState {
+        ConnectionState::connected_to(transport.clone())
+    }
+
+    fn tlv(type_val: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&type_val.to_le_bytes());
+        buf.extend_from_slice(&((4 + payload.len()) as u16).to_le_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    fn ok_response(payload: &[u8]) -> Vec<u8> {
+        tlv(StructureTypes::Ok as u16, payload)
+    }
+
+    /// A configuration with a single filter.
+    fn single_filter() -> Config {
+        test_config([(1000.0, true)])
+    }
+
+    fn value(structure: &impl TlvStructure) -> Vec<u8> {
+        let mut buf = Vec::new();
+        structure.encode_value(&mut buf);
+        buf
+    }
+
+    fn encode_config(config: &Config) -> Vec<u8> {
+        let mut buf = Vec::new();
+        config.encode(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn not_connected() {
+        let mut connection = ConnectionState::default();
+        assert!(connection.save_config().is_err());
+        assert!(connection.load_config().is_err());
+        assert!(!connection.check_connection());
+    }
+
+    #[test]
+    fn commands_are_addressed_by_serial() {
+        let first = MemoryTransport::with_responses(vec![ok_response(&[]); 2]);
+        let second = MemoryTransport::with_responses(vec![ok_response(&[]); 2]);
+        let mut connection = connect(&first);
+        connection
+            .devices
+            .insert("second".to_owned(), ConnectedDevice::new(second.clone()));
+
+        connection.save_config().unwrap();
+        connection
+            .with_device(Some("second"), ConnectionState::save_config)
+            .unwrap();
+        assert_eq!(first.state().written.len(), 1);
+        assert_eq!(second.state().written.len(), 1);
+        // Addressing a device doesn't change the current one
+        assert_eq!(connection.current.as_deref(), Some("test"));
+        assert!(matches!(
+            connection.with_device(Some("missing"), ConnectionState::save_config),
+            Err(Error::NotConnected)
+        ));
+
+        let targets = ["test", "second", "missing"].map(str::to_owned);
+        let results = connection.broadcast_config(&targets, &single_filter());
+        assert_eq!(first.state().written.len(), 2);
+        assert_eq!(second.state().written.len(), 2);
+        let errors: Vec<_> = results
+            .iter()
+            .map(|r| (r.serial_number.as_str(), r.error.as_ref().map(Error::kind)))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                ("test", None),
+                ("second", None),
+                ("missing", Some("not_connected"))
+            ]
+        );
+    }
+
+    #[test]
+    fn write_config_works() {
+        let transport = MemoryTransport::with_responses(vec![ok_response(&[])]);
+        let mut connection = connect(&transport);
+        let config = single_filter();
+        connection.write_config(&config).unwrap();
+
+        let mut expected = Vec::new();
+        SetConfiguration::new(
+            SetPreprocessingConfiguration::new(&config.preprocessing),
+            SetFilterConfiguration::new(&config.filters, &Capabilities::default()).unwrap(),
+            SetPcm3060Configuration::new(&config.codec),
+        )
+        .write_as_binary(&mut expected);
+        assert_eq!(transport.state().written, vec![expected]);
+    }
+
+    #[test]
+    fn writes_report_filters_above_nyquist() {
+        let transport = MemoryTransport::with_responses(vec![ok_response(&[])]);
+        let mut config = single_filter();
+        config
+            .filters
+            .add(PeakingFilter::new(23000.0, 0.7, 3.0).unwrap().into(), true);
+        // Firmwares which don't report their sample rates may be playing at 44.1kHz
+        assert_eq!(
+            connect(&transport).write_config(&config).unwrap().warnings,
+            vec!["Filter 2 has an f0 of 23000 Hz, above the 22050 Hz limit when playing at 44100 Hz."]
+        );
+    }
+
+    #[test]
+    fn identical_writes_are_skipped() {
+        let transport = MemoryTransport::with_responses(vec![ok_response(&[]); 4]);
+        let mut connection = connect(&transport);
+        let config = single_filter();
+        connection.write_config(&config).unwrap();
+        connection.write_config(&config).unwrap();
+        assert_eq!(transport.state().written.len(), 1);
+
+        let mut changed = single_filter();
+        changed.codec = Codec::new(false, false, true, false);
+        connection.write_config(&changed).unwrap();
+        assert_eq!(transport.state().written.len(), 2);
+
+        // A factory reset changes the active configuration behind our back
+        connection.factory_reset().unwrap();
+        connection.write_config(&changed).unwrap();
+        assert_eq!(transport.state().written.len(), 4);
+    }
+
+    #[test]
+    fn save_config_works() {
+        let transport = MemoryTransport::with_responses(vec![ok_response(&[])]);
+        connect(&transport).save_config().unwrap();
+        assert_eq!(transport.state().written, vec![vec![7, 0, 4, 0]]);
+    }
+
+    #[test]
+    fn factory_reset_works() {
+        let transport = MemoryTransport::with_responses(vec![ok_response(&[])]);
+        connect(&transport).factory_reset().unwrap();
+        assert_eq!(transport.state().written, vec![vec![8, 0, 4, 0]]);
+    }
+
+    #[test]
+    fn load_config_works() {
+        let config = single_filter();
+        let transport = MemoryTransport::with_responses(vec![ok_response(&encode_config(&config))]);
+        let loaded = connect(&transport).load_config().unwrap();
+        assert_eq!(transport.state().written, vec![vec![6, 0, 4, 0]]);
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&config).unwrap()
+        );
+    }
+
+    #[test]
+    fn verify_reports_dropped_filters() {
+        let mut config = single_filter();
+        config
+            .filters
+            .add(PeakingFilter::new(2000.0, 1.0, 1.0).unwrap().into(), true);
+        let transport = MemoryTransport::with_responses(vec![
+            ok_response(&[]),
+            ok_response(&encode_config(&single_filter())),
+        ]);
+        let mut connection = connect(&transport);
+        connection.verify_writes = true;
+        match connection.write_config(&config) {
+            Err(Error::Verification { mismatches }) => {
+                assert_eq!(mismatches, vec!["filters: sent 2 entries, device has 1"])
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert_eq!(transport.state().written[1], vec![5, 0, 4, 0]);
+
+        // Retrying sends the configuration again rather than assuming the device has it
+        transport
+            .state()
+            .responses
+            .extend([ok_response(&[]), ok_response(&encode_config(&config))]);
+        connection.write_config(&config).unwrap();
+        assert_eq!(transport.state().written.len(), 4);
+
+        // Nothing is read back unless verification is enabled
+        let transport = MemoryTransport::with_responses(vec![ok_response(&[])]);
+        connect(&transport).write_config(&config).unwrap();
+        assert_eq!(transport.state().written.len(), 1);
+    }
+
+    #[test]
+    fn load_active_config_works() {
+        let config = single_filter();
+        let transport = MemoryTransport::with_responses(vec![ok_response(&encode_config(&config))]);
+        let loaded = connect(&transport).load_active_config().unwrap();
+        assert_eq!(transport.state().written, vec![vec![5, 0, 4, 0]]);
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&config).unwrap()
+        );
+    }
+
+    #[test]
+    fn diff_compares_each_structure() {
+        let config = single_filter();
+        assert_eq!(config.diff(&single_filter()), ConfigDiff::default());
+
+        let mut changed = single_filter();
+        changed.codec = Codec::new(false, false, true, false);
+        changed
+            .filters
+            .add(PeakingFilter::new(100.0, 1.0, 1.0).unwrap().into(), true);
+        assert_eq!(
+            config.diff(&changed),
+            ConfigDiff {
+                preprocessing: false,
+                filters: true,
+                codec: true
+            }
+        );
+
+        // Disabled filters never reach the device
+        changed = single_filter();
+        changed
+            .filters
+            .add(PeakingFilter::new(100.0, 1.0, 1.0).unwrap().into(), false);
+        assert_eq!(config.diff(&changed), ConfigDiff::default());
+    }
+
+    #[test]
+    fn load_config_reassembles_packets() {
+        let mut config = single_filter();
+        for i in 0..10 {
+            let f0 = 100.0 * (i + 2) as f32;
+            config
+                .filters
+                .add(PeakingFilter::new(f0, 1.4, -1.0).unwrap().into(), true);
+        }
+        let response = ok_response(&encode_config(&config));
+        assert!(response.len() > 64);
+        let transport =
+            MemoryTransport::with_responses(response.chunks(64).map(|c| c.to_vec()).collect());
+        let loaded = connect(&transport).load_config().unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&config).unwrap()
+        );
+    }
+
+    fn round_trip<T: TlvStructure>(structure: &T) -> T {
+        let mut buf = Vec::new();
+        structure.encode(&mut buf);
+        let mut cur = TlvReader::new(&buf);
+        let decoded = T::decode(&mut cur).unwrap();
+        assert!(cur.is_empty());
+        decoded
+    }
+
+    #[test]
+    fn preprocessing_round_trips() {
+        let preprocessing = Preprocessing {
+            preamp: -6.5,
+            post_eq_gain: 3.0,
+            reverse_stereo: true,
+        };
+        let decoded = round_trip(&preprocessing);
+        assert!((decoded.preamp - preprocessing.preamp).abs() < 1e-4);
+        assert!((decoded.post_eq_gain - preprocessing.post_eq_gain).abs() < 1e-4);
+        assert!(decoded.reverse_stereo);
+    }
+
+    #[test]
+    fn codec_round_trips() {
+        let codec = Codec::new(true, false, false, true);
+        assert_eq!(
+            serde_json::to_value(round_trip(&codec)).unwrap(),
+            serde_json::to_value(&codec).unwrap()
+        );
+    }
+
+    #[test]
+    fn filters_round_trip() {
+        let mut filters = Filters::default();
+        filters.add(LowpassFilter::new(20000.0, 0.7).unwrap().into(), true);
+        filters.add(HighpassFilter::new(20.0, 0.5).unwrap().into(), true);
+        filters.add(BandpassSkirtFilter::new(500.0, 1.0).unwrap().into(), true);
+        filters.add(BandpassPeakFilter::new(600.0, 1.1).unwrap().into(), true);
+        filters.add(NotchFilter::new(50.0, 10.0).unwrap().into(), true);
+        filters.add(AllpassFilter::new(1000.0, 0.7).unwrap().into(), true);
+        filters.add(PeakingFilter::new(3000.0, 2.0, -4.5).unwrap().into(), true);
+        filters.add(LowShelfFilter::new(100.0, 0.7, 3.0).unwrap().into(), true);
+        filters.add(
+            HighShelfFilter::new(8000.0, 0.7, -2.0).unwrap().into(),
+            true,
+        );
+        filters.add(
+            CustomIIRFilter::new(1.0, -1.9, 0.91, 0.5, 0.1, -0.25).into(),
+            true,
+        );
+        assert_eq!(
+            serde_json::to_value(round_trip(&filters)).unwrap(),
+            serde_json::to_value(&filters).unwrap()
+        );
+
+        // Disabled filters are not sent to the device
+        filters.add(PeakingFilter::new(100.0, 1.0, 1.0).unwrap().into(), false);
+        assert_eq!(
+            serde_json::to_value(round_trip(&filters))
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .len(),
+            10
+        );
+    }
+
+    #[test]
+    fn version_info_round_trips() {
+        let mut version = VersionInfo {
+            current_version: 4,
+            minimum_supported_version: 2,
+            git_hash: "abc123".to_owned(),
+            pico_sdk_version: "1.5.1".to_owned(),
+            capabilities: None,
+        };
+        assert_eq!(
+            serde_json::to_value(round_trip(&version)).unwrap(),
+            serde_json::to_value(&version).unwrap()
+        );
+
+        version.capabilities = Some(FirmwareCapabilities {
+            max_filters: 16,
+            per_channel_eq: true,
+            codec: true,
+            filter_types: vec![0, 6],
+            filter_limits: FilterLimits::default(),
+            sample_rates: vec![48000],
+        });
+        assert_eq!(
+            serde_json::to_value(round_trip(&version)).unwrap(),
+            serde_json::to_value(&version).unwrap()
+        );
+    }
+
+    #[test]
+    fn decode_checks_the_type() {
+        let mut buf = Vec::new();
+        Codec::default().encode(&mut buf);
+        assert!(Preprocessing::decode(&mut TlvReader::new(&buf)).is_err());
+    }
+
+    #[test]
+    fn malformed_config_is_an_error() {
+        let config = encode_config(&single_filter());
+        let filters = value(&single_filter().filters);
+
+        // Truncated in the middle of a filter
+        let truncated = tlv(
+            StructureTypes::FilterConfiguration as u16,
+            &filters[..filters.len() - 2],
+        );
+        // An unknown filter type
+        let mut unknown = filters.clone();
+        unknown[0] = 0xff;
+        let unknown = tlv(StructureTypes::FilterConfiguration as u16, &unknown);
+        // A TLV claiming to be longer than the response
+        let mut overlong = config.clone();
+        overlong[2] += 1;
+        // Preprocessing missing its reserved bytes
+        let short = tlv(
+            StructureTypes::PreProcessingConfiguration as u16,
+            &value(&single_filter().preprocessing)[..10],
+        );
+        // Trailing bytes after the codec settings
+        let long = tlv(
+            StructureTypes::Pcm3060Configuration as u16,
+            &[value(&single_filter().codec), vec![0]].concat(),
+        );
+
+        for body in [truncated, unknown, overlong, short, long, vec![0, 2, 2, 0]] {
+            assert!(parse_config(&ok_response(&body)).is_err());
+        }
+        assert!(parse_config(&[]).is_err());
+        assert!(parse_config(&ok_response(&config)[..10]).is_err());
+    }
+
+    #[test]
+    fn malformed_version_info_is_an_error() {
+        let mut version = Vec::new();
+        version.extend_from_slice(&3u16.to_le_bytes());
+        version.extend_from_slice(&1u16.to_le_bytes());
+        version.extend_from_slice(&[0u8; 4]);
+        version.extend_from_slice(b"abc123");
+        let response = ok_response(&tlv(StructureTypes::VersionStatus as u16, &version));
+        assert!(VersionInfo::from_buf(&response).is_err());
+        assert!(VersionInfo::from_buf(&response[..6]).is_err());
+    }
+
+    #[test]
+    fn padding_after_version_info_is_ignored() {
+        let info = VersionInfo {
+            current_version: 4,
+            minimum_supported_version: 4,
+            git_hash: "abc123".to_owned(),
+            pico_sdk_version: "1.5.1".to_owned(),
+            capabilities: None,
+        };
+        for padding in [vec![0u8; 3], vec![0xff; 8]] {
+            let mut version = Vec::new();
+            info.encode_value(&mut version);
+            version.extend(padding);
+            let response = ok_response(&tlv(StructureTypes::VersionStatus as u16, &version));
+            let decoded = VersionInfo::from_buf(&response).unwrap();
+            assert_eq!(decoded.current_version, 4);
+            assert!(decoded.capabilities.is_none());
+        }
+    }
+
+    #[test]
+    fn read_version_info_works() {
+        let mut version = Vec::new();
+        version.extend_from_slice(&3u16.to_le_bytes());
+        version.extend_from_slice(&1u16.to_le_bytes());
+        version.extend_from_slice(&[0u8; 4]);
+        version.extend_from_slice(b"abc123\0");
+        version.extend_from_slice(b"1.5.1\0");
+        let response = ok_response(&tlv(StructureTypes::VersionStatus as u16, &version));
+
+        let transport = MemoryTransport::with_responses(vec![response]);
+        let info = connect(&transport).read_version_info().unwrap();
+        assert_eq!(info.current_version, 3);
+        assert_eq!(info.minimum_supported_version, 1);
+        assert_eq!(info.git_hash, "abc123");
+        assert_eq!(info.pico_sdk_version, "1.5.1");
+    }
+
+    fn version_response(minimum_supported_version: u16, current_version: u16) -> Vec<u8> {
+        let mut buf = Vec::new();
+        VersionInfo {
+            current_version,
+            minimum_supported_version,
+            git_hash: "abc123".to_owned(),
+            pico_sdk_version: "1.5.1".to_owned(),
+            capabilities: None,
+        }
+        .encode(&mut buf);
+        ok_response(&buf)
+    }
+
+    #[test]
+    fn incompatible_firmwares_are_refused() {
+        for (min, current) in [
+            (PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2),
+            (1, PROTOCOL_VERSION - 1),
+        ] {
+            let transport = MemoryTransport::with_responses(vec![version_response(min, current)]);
+            let mut connection = connect(&transport);
+            assert!(matches!(
+                connection.open_negotiated(),
+                Err(Error::VersionMismatch { .. })
+            ));
+            assert!(connection.devices.is_empty());
+            // Nothing is sent in a layout the firmware might misread
+            assert_eq!(transport.state().written.len(), 1);
+        }
+    }
+
+    #[test]
+    fn reboot_bootloader_works() {
+        let transport = MemoryTransport::default();
+        connect(&transport).reboot_bootloader().unwrap();
+        assert_eq!(
+            transport.state().control_transfers,
+            vec![(LIBUSB_REQUEST_TYPE_VENDOR, 0, 0x2e8a, 0, vec![])]
+        );
+    }
+
+    #[test]
+    fn codec_settings_follow_the_capabilities() {
+        let transport = MemoryTransport::with_responses(vec![ok_response(&[])]);
+        let mut connection = connect(&transport);
+        connection
+            .device_mut()
+            .unwrap()
+            .capabilities
+            .structures
+            .retain(|&s| s != StructureTypes::Pcm3060Configuration as u16);
+        let config = single_filter();
+        connection.write_config(&config).unwrap();
+
+        let mut expected = Vec::new();
+        SetConfiguration::new(
+            SetPreprocessingConfiguration::new(&config.preprocessing),
+            SetFilterConfiguration::new(&config.filters, &Capabilities::default()).unwrap(),
+            SetPcm3060Configuration::new(&config.codec),
+        )
+        .without_codec()
+        .write_as_binary(&mut expected);
+        assert_eq!(transport.state().written, vec![expected]);
+    }
+
+    #[test]
+    fn model_quirks_are_respected() {
+        let mut model = Registry::default().find(0x2e8a, 0xfedd).unwrap().clone();
+        model.codec = CodecType::None;
+        model.quirks.push(Quirk::NoBootloaderReboot);
+
+        let plain = MemoryTransport::with_responses(vec![ok_response(&[])]);
+        connect(&plain).write_config(&single_filter()).unwrap();
+
+        let transport = MemoryTransport::with_responses(vec![ok_response(&[])]);
+        let mut connection = connect(&transport);
+        let device = connection.device_mut().unwrap();
+        model.apply(&mut device.transfer_mode, &mut device.capabilities);
+        device.model = Some(model);
+        connection.write_config(&single_filter()).unwrap();
+        // The codec settings are left out
+        assert!(transport.state().written[0].len() < plain.state().written[0].len());
+
+        assert!(matches!(
+            connection.reboot_bootloader(),
+            Err(Error::Validation(_))
+        ));
+        assert!(transport.state().control_transfers.is_empty());
+    }
+
+    #[test]
+    fn nok_is_an_error() {
+        let nok = tlv(StructureTypes::Nok as u16, &[]);
+        let transport = MemoryTransport::with_responses(vec![nok.clone(), nok]);
+        let mut connection = connect(&transport);
+        assert!(matches!(
+            connection.write_config(&single_filter()),
+            Err(Error::Nok { .. })
+        ));
+        assert!(matches!(connection.load_config(), Err(Error::Nok { .. })));
+
+        let transport = MemoryTransport::with_responses(vec![tlv(0x42, &[])]);
+        assert!(matches!(
+            connect(&transport).save_config(),
+            Err(Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn read_timeout_is_an_error() {
+        let transport = MemoryTransport::default();
+        assert!(connect(&transport).save_config().is_err());
+    }
+
+    #[test]
+    fn disconnect_is_detected() {
+        let transport = MemoryTransport::default();
+        let mut connection = connect(&transport);
+        assert!(connection.check_connection());
+        transport.state().disconnected = true;
+        assert!(!connection.check_connection());
+        assert!(connection.devices.is_empty());
+    }
+}
@@ -1,12 +1,10 @@
-use std::io::{Read, Seek, SeekFrom};
-
-use byteorder::{LittleEndian, ReadBytesExt};
-
+use crate::error::Error;
 use crate::filters::{
     AllpassFilter, BandpassPeakFilter, BandpassSkirtFilter, CustomIIRFilter, FilterConfig,
     FilterName, FreqGainQualFilter, FreqQualFilter, HighShelfFilter, HighpassFilter,
     LowShelfFilter, LowpassFilter, NotchFilter, PeakingFilter,
 };
+use crate::tlv::TlvReader;
 
 #[derive(Debug, Clone, Copy)]
 enum FilterType {
@@ -33,7 +31,7 @@ impl<T: LowLevelFilter> Discriminant for T {
 }
 
 pub trait DeserializeFilter: Sized {
-    fn from_reader(cur: impl Read) -> Result<Self, String>;
+    fn from_reader(cur: &mut TlvReader) -> Result<Self, Error>;
 }
 
 trait LowLevelFilter {
@@ -100,9 +98,9 @@ impl LowLevelFilter for CustomIIRFilter {
     }
 }
 
-pub fn read_filter(mut cur: impl Read + Seek) -> Result<FilterConfig, String> {
-    let filter_type = cur.read_u8().unwrap();
-    let _ = cur.seek(SeekFrom::Current(3)); // reserved bytes
+pub fn read_filter(cur: &mut TlvReader) -> Result<FilterConfig, Error> {
+    let filter_type = cur.read_u8()?;
+    cur.skip(3)?; // reserved bytes
 
     let filter: FilterConfig = match filter_type {
         x if x == LowpassFilter::discriminant() => LowpassFilter::from_reader(cur)?.into(),
@@ -120,7 +118,7 @@ pub fn read_filter(mut cur: impl Read + Seek) -> Result<FilterConfig, String> {
         x if x == HighShelfFilter::discriminant() => HighShelfFilter::from_reader(cur)?.into(),
         x if x == CustomIIRFilter::discriminant() => CustomIIRFilter::from_reader(cur)?.into(),
         other => {
-            return Err(format!("Unknown filter type: {}", other));
+            return Err(Error::Protocol(format!("Unknown filter type: {}", other)));
         }
     };
 
@@ -184,13 +182,13 @@ impl StructuralPayload for CustomIIRFilter {
 }
 
 impl DeserializeFilter for CustomIIRFilter {
-    fn from_reader(mut cur: impl Read) -> Result<Self, String> {
-        let a0 = cur.read_f64::<LittleEndian>().unwrap();
-        let a1 = cur.read_f64::<LittleEndian>().unwrap();
-        let a2 = cur.read_f64::<LittleEndian>().unwrap();
-        let b0 = cur.read_f64::<LittleEndian>().unwrap();
-        let b1 = cur.read_f64::<LittleEndian>().unwrap();
-        let b2 = cur.read_f64::<LittleEndian>().unwrap();
+    fn from_reader(cur: &mut TlvReader) -> Result<Self, Error> {
+        let a0 = cur.read_f64()?;
+        let a1 = cur.read_f64()?;
+        let a2 = cur.read_f64()?;
+        let b0 = cur.read_f64()?;
+        let b1 = cur.read_f64()?;
+        let b2 = cur.read_f64()?;
         Ok(Self::new(a0, a1, a2, b0, b1, b2))
     }
 }
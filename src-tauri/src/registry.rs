@@ -0,0 +1,215 @@
+use std::{fs, path::Path};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{capabilities::Capabilities, commands::StructureTypes, transport::TransferMode};
+
+/// The name of the user's registry file, in the app's config directory.
+pub const REGISTRY_FILE: &str = "devices.json";
+/// Where the generated udev rules are meant to be installed on Linux.
+pub const UDEV_RULES_PATH: &str = "/etc/udev/rules.d/70-headphones-toolbox.rules";
+
+/// The codec a board drives, which decides whether codec settings are sent to it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CodecType {
+    #[default]
+    Pcm3060,
+    /// The board has no configurable codec.
+    None,
+}
+
+/// Known deviations of a board or firmware fork from the protocol.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Quirk {
+    /// Never use chunked transfers, even if the firmware offers them.
+    SingleFrame,
+    /// The board can't be rebooted into its bootloader over USB.
+    NoBootloaderReboot,
+}
+
+/// A kind of device the toolbox can configure.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeviceModel {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub name: String,
+    /// The class of the interface carrying the configuration protocol.
+    #[serde(default = "vendor_specific_class")]
+    pub interface_class: u8,
+    #[serde(default)]
+    pub codec: CodecType,
+    #[serde(default)]
+    pub quirks: Vec<Quirk>,
+}
+
+fn vendor_specific_class() -> u8 {
+    0xff
+}
+
+impl DeviceModel {
+    pub fn has_quirk(&self, quirk: Quirk) -> bool {
+        self.quirks.contains(&quirk)
+    }
+
+    /// Narrows what was negotiated with the firmware to what the board can actually do.
+    pub fn apply(&self, transfer_mode: &mut TransferMode, capabilities: &mut Capabilities) {
+        if self.codec == CodecType::None {
+            capabilities
+                .structures
+                .retain(|&s| s != StructureTypes::Pcm3060Configuration as u16);
+        }
+        if self.has_quirk(Quirk::SingleFrame) {
+            *transfer_mode = TransferMode::SingleFrame;
+        }
+    }
+}
+
+/// The devices we look for, the built-in models plus any the user has described.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Registry(Vec<DeviceModel>);
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self(vec![DeviceModel {
+            vendor_id: 0x2e8a,
+            product_id: 0xfedd,
+            name: "Ploopy Headphones".to_owned(),
+            interface_class: vendor_specific_class(),
+            codec: CodecType::Pcm3060,
+            quirks: Vec::new(),
+        }])
+    }
+}
+
+impl Registry {
+    /// Loads the built-in models, extended by the user's registry file in `config_dir` if there is
+    /// one. Entries in the file replace built-in models with the same VID/PID.
+    pub fn load(config_dir: &Path) -> Self {
+        let mut registry = Self::default();
+        let path = config_dir.join(REGISTRY_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(x) => x,
+            Err(_) => return registry,
+        };
+        match serde_json::from_str::<Vec<DeviceModel>>(&contents) {
+            Ok(models) => {
+                info!(
+                    "Loaded {} device models from {}",
+                    models.len(),
+                    path.display()
+                );
+                registry.extend(models);
+            }
+            Err(e) => warn!("Ignoring {}: {}", path.display(), e),
+        }
+        registry
+    }
+
+    fn extend(&mut self, models: Vec<DeviceModel>) {
+        for model in models {
+            self.0
+                .retain(|m| (m.vendor_id, m.product_id) != (model.vendor_id, model.product_id));
+            self.0.push(model);
+        }
+    }
+
+    pub fn find(&self, vendor_id: u16, product_id: u16) -> Option<&DeviceModel> {
+        self.0
+            .iter()
+            .find(|m| m.vendor_id == vendor_id && m.product_id == product_id)
+    }
+
+    /// A udev rules file giving the logged in user access to every registered model, so the
+    /// toolbox can open them without root.
+    pub fn udev_rules(&self) -> String {
+        let mut rules = format!(
+            "# Generated by Ploopy Headphones Toolbox, install as {}\n\
+             # then run `sudo udevadm control --reload-rules && sudo udevadm trigger`\n",
+            UDEV_RULES_PATH
+        );
+        for model in &self.0 {
+            rules += &format!(
+                "\n# {}\nSUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", \
+                 ATTRS{{idProduct}}==\"{:04x}\", MODE=\"0660\", TAG+=\"uaccess\"\n",
+                model.name, model.vendor_id, model.product_id
+            );
+        }
+        rules
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_models_extend_the_registry() {
+        let mut registry = Registry::default();
+        let models: Vec<DeviceModel> = serde_json::from_str(
+            r#"[
+                {"vendor_id": 11914, "product_id": 65245, "name": "Renamed", "quirks": ["single_frame"]},
+                {"vendor_id": 4660, "product_id": 22136, "name": "Other DAC", "interface_class": 254, "codec": "none"}
+            ]"#,
+        )
+        .unwrap();
+        registry.extend(models);
+
+        let ploopy = registry.find(0x2e8a, 0xfedd).unwrap();
+        assert_eq!(ploopy.name, "Renamed");
+        assert_eq!(ploopy.interface_class, 0xff);
+        assert!(ploopy.has_quirk(Quirk::SingleFrame));
+
+        let other = registry.find(0x1234, 0x5678).unwrap();
+        assert_eq!(other.interface_class, 0xfe);
+        assert_eq!(other.codec, CodecType::None);
+        assert!(registry.find(0x1234, 0x0000).is_none());
+    }
+
+    #[test]
+    fn quirks_narrow_the_negotiated_capabilities() {
+        let mut model = Registry::default().0.remove(0);
+        model.codec = CodecType::None;
+        model.quirks.push(Quirk::SingleFrame);
+
+        let mut transfer_mode = TransferMode::Chunked {
+            max_chunk_len: 64,
+            max_transfer_len: 4096,
+        };
+        let mut capabilities = Capabilities::default();
+        model.apply(&mut transfer_mode, &mut capabilities);
+        assert_eq!(transfer_mode, TransferMode::SingleFrame);
+        assert!(!capabilities.supports(StructureTypes::Pcm3060Configuration));
+    }
+
+    #[test]
+    fn udev_rules_cover_every_model() {
+        let mut registry = Registry::default();
+        registry.extend(vec![DeviceModel {
+            vendor_id: 0x1234,
+            product_id: 0xabcd,
+            name: "Other DAC".to_owned(),
+            interface_class: vendor_specific_class(),
+            codec: CodecType::None,
+            quirks: Vec::new(),
+        }]);
+        let rules = registry.udev_rules();
+        assert!(rules.contains(
+            "SUBSYSTEM==\"usb\", ATTRS{idVendor}==\"2e8a\", ATTRS{idProduct}==\"fedd\", \
+             MODE=\"0660\", TAG+=\"uaccess\""
+        ));
+        assert!(rules.contains("# Other DAC\n"));
+        assert!(rules.contains("ATTRS{idVendor}==\"1234\", ATTRS{idProduct}==\"abcd\""));
+    }
+
+    #[test]
+    fn bad_files_are_ignored() {
+        let dir = std::env::temp_dir().join(format!("registry-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(REGISTRY_FILE), "not json").unwrap();
+        assert_eq!(Registry::load(&dir), Registry::default());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
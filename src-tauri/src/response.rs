@@ -0,0 +1,442 @@
+use std::{f64::consts::PI, fmt};
+
+use serde::Serialize;
+
+use crate::filters::{CustomIIRFilter, Filters, ToCustomIIR};
+
+/// The response of a filter, or a chain of them, at each frequency of a grid.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Response {
+    pub magnitude_db: Vec<f64>,
+    pub phase_degrees: Vec<f64>, // Wrapped to (-180, 180]
+    pub group_delay_ms: Vec<f64>,
+}
+
+/// The response of each filter in a configuration, enabled or not, and of the enabled filters
+/// applied one after another.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FiltersResponse {
+    pub filters: Vec<Response>,
+    pub combined: Response,
+}
+
+/// A complex number, just enough of one to evaluate a biquad.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    const ONE: Complex = Complex { re: 1.0, im: 0.0 };
+
+    /// e^-jw
+    fn unit(w: f64) -> Self {
+        Self {
+            re: w.cos(),
+            im: -w.sin(),
+        }
+    }
+
+    fn scale(self, k: f64) -> Self {
+        Self {
+            re: self.re * k,
+            im: self.im * k,
+        }
+    }
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            re: self.re + other.re,
+            im: self.im + other.im,
+        }
+    }
+
+    fn mul(self, other: Self) -> Self {
+        Self {
+            re: self.re * other.re - self.im * other.im,
+            im: self.re * other.im + self.im * other.re,
+        }
+    }
+
+    fn div(self, other: Self) -> Self {
+        let norm = other.re * other.re + other.im * other.im;
+        Self {
+            re: (self.re * other.re + self.im * other.im) / norm,
+            im: (self.im * other.re - self.re * other.im) / norm,
+        }
+    }
+
+    fn abs(self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    fn arg(self) -> f64 {
+        self.im.atan2(self.re)
+    }
+}
+
+impl fmt::Display for Complex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Adding zero turns -0 into 0, which reads better
+        let re = self.re + 0.0;
+        if self.im == 0.0 {
+            write!(f, "{:.4}", re)
+        } else {
+            write!(f, "{:.4}{:+.4}j", re, self.im)
+        }
+    }
+}
+
+/// Where a biquad's poles and zeros lie, and the most it boosts any frequency.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BiquadAnalysis {
+    pub poles: Vec<Complex>,
+    pub zeros: Vec<Complex>,
+    pub max_gain_db: f64,
+}
+
+impl BiquadAnalysis {
+    /// A filter is only stable with its poles strictly inside the unit circle.
+    pub fn is_stable(&self) -> bool {
+        self.poles.iter().all(|p| p.abs() < 1.0)
+    }
+}
+
+/// How many frequencies, between DC and Nyquist, are checked for the maximum gain.
+const GAIN_STEPS: usize = 1024;
+/// The band searched for the loudest frequency when calculating headroom.
+const AUDIBLE_BAND: (f64, f64) = (20.0, 20000.0);
+/// How many frequencies across the audible band are checked, spaced logarithmically.
+const AUDIBLE_STEPS: usize = 512;
+/// How many more frequencies are checked between the neighbours of the loudest one found.
+const REFINE_STEPS: usize = 64;
+
+/// The loudest the enabled filters get in the audible band, and the preamp which keeps the
+/// output at or below 0dB.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Headroom {
+    pub peak_gain_db: f64, // Including the post-EQ gain
+    pub peak_frequency: f64,
+    pub preamp_db: f64,
+}
+
+/// The finite roots of c0 z^2 + c1 z + c2, which are where c0 + c1 z^-1 + c2 z^-2 is zero.
+fn roots(c: [f64; 3]) -> Vec<Complex> {
+    let real = |re| Complex { re, im: 0.0 };
+    if c[0] == 0.0 {
+        return if c[1] == 0.0 {
+            Vec::new()
+        } else {
+            vec![real(-c[2] / c[1])]
+        };
+    }
+    let discriminant = c[1] * c[1] - 4.0 * c[0] * c[2];
+    let centre = -c[1] / (2.0 * c[0]);
+    let offset = discriminant.abs().sqrt() / (2.0 * c[0]);
+    if discriminant >= 0.0 {
+        vec![real(centre + offset), real(centre - offset)]
+    } else {
+        vec![
+            Complex {
+                re: centre,
+                im: offset,
+            },
+            Complex {
+                re: centre,
+                im: -offset,
+            },
+        ]
+    }
+}
+
+/// Finds a biquad's poles and zeros, and its largest gain on the way from DC to Nyquist.
+pub fn analyze(filter: &CustomIIRFilter) -> BiquadAnalysis {
+    let max_gain_db = (0..=GAIN_STEPS)
+        .map(|i| {
+            let w = PI * i as f64 / GAIN_STEPS as f64;
+            20.0 * evaluate(filter, w).h.abs().log10()
+        })
+        .fold(f64::NEG_INFINITY, f64::max);
+    BiquadAnalysis {
+        poles: roots([filter.a0, filter.a1, filter.a2]),
+        zeros: roots([filter.b0, filter.b1, filter.b2]),
+        max_gain_db,
+    }
+}
+
+/// The value and group delay, in samples, of a filter at a frequency.
+#[derive(Debug, Clone, Copy)]
+struct Point {
+    h: Complex,
+    group_delay: f64,
+}
+
+/// Evaluates c0 + c1 z^-1 + c2 z^-2 at z = e^jw, along with its group delay contribution
+/// Re(sum k ck z^-k / sum ck z^-k).
+fn polynomial(c: [f64; 3], w: f64) -> (Complex, f64) {
+    let z1 = Complex::unit(w);
+    let z2 = Complex::unit(2.0 * w);
+    let value = Complex::ONE
+        .scale(c[0])
+        .add(z1.scale(c[1]))
+        .add(z2.scale(c[2]));
+    let ramp = z1.scale(c[1]).add(z2.scale(2.0 * c[2]));
+    (value, ramp.div(value).re)
+}
+
+fn evaluate(filter: &CustomIIRFilter, w: f64) -> Point {
+    let (numerator, numerator_delay) = polynomial([filter.b0, filter.b1, filter.b2], w);
+    let (denominator, denominator_delay) = polynomial([filter.a0, filter.a1, filter.a2], w);
+    Point {
+        h: numerator.div(denominator),
+        group_delay: numerator_delay - denominator_delay,
+    }
+}
+
+impl Response {
+    fn from_points(points: &[Point], sample_rate: f64) -> Self {
+        Self {
+            magnitude_db: points.iter().map(|p| 20.0 * p.h.abs().log10()).collect(),
+            phase_degrees: points.iter().map(|p| p.h.arg().to_degrees()).collect(),
+            group_delay_ms: points
+                .iter()
+                .map(|p| p.group_delay / sample_rate * 1000.0)
+                .collect(),
+        }
+    }
+}
+
+/// Evaluates every filter on a grid of frequencies in Hz, as it runs at `sample_rate`.
+pub fn filters_response(
+    filters: &Filters,
+    frequencies: &[f64],
+    sample_rate: f64,
+) -> FiltersResponse {
+    let w: Vec<f64> = frequencies
+        .iter()
+        .map(|f| 2.0 * PI * f / sample_rate)
+        .collect();
+    let mut combined = vec![
+        Point {
+            h: Complex::ONE,
+            group_delay: 0.0,
+        };
+        w.len()
+    ];
+    let mut responses = Vec::new();
+    for (filter, enabled) in filters.all() {
+        let iir = filter.to_custom(sample_rate);
+        let points: Vec<Point> = w.iter().map(|&w| evaluate(&iir, w)).collect();
+        if enabled {
+            for (total, point) in combined.iter_mut().zip(&points) {
+                total.h = total.h.mul(point.h);
+                total.group_delay += point.group_delay;
+            }
+        }
+        responses.push(Response::from_points(&points, sample_rate));
+    }
+    FiltersResponse {
+        filters: responses,
+        combined: Response::from_points(&combined, sample_rate),
+    }
+}
+
+/// Finds the peak gain of the enabled filters plus `post_eq_gain_db` across the audible band, at
+/// whichever of the sample rates is loudest, and recommends a preamp which cuts it to
+/// `-safety_margin_db`. The recommended preamp never boosts.
+pub fn headroom(
+    filters: &Filters,
+    post_eq_gain_db: f64,
+    safety_margin_db: f64,
+    sample_rates: &[u32],
+) -> Headroom {
+    let (low, high) = AUDIBLE_BAND;
+    let step = (high / low).powf(1.0 / AUDIBLE_STEPS as f64);
+    let mut frequencies: Vec<f64> = (0..=AUDIBLE_STEPS)
+        .map(|i| low * step.powi(i as i32))
+        .collect();
+    // A narrow peak can fall between the grid points, but not away from its filter's f0
+    frequencies.extend(
+        filters
+            .enabled()
+            .filter_map(|f| f.f0())
+            .map(f64::from)
+            .filter(|f0| (low..=high).contains(f0)),
+    );
+    let peak = loudest(
+        filters,
+        &frequencies,
+        sample_rates,
+        (f64::NEG_INFINITY, low),
+    );
+    // Overlapping filters can peak away from every f0, so look closer around the loudest point
+    let frequencies: Vec<f64> = (0..=REFINE_STEPS)
+        .map(|i| peak.1 / step * step.powf(2.0 * i as f64 / REFINE_STEPS as f64))
+        .filter(|f| (low..=high).contains(f))
+        .collect();
+    let peak = loudest(filters, &frequencies, sample_rates, peak);
+    let peak_gain_db = peak.0 + post_eq_gain_db;
+    Headroom {
+        peak_gain_db,
+        peak_frequency: peak.1,
+        preamp_db: (-peak_gain_db - safety_margin_db).min(0.0),
+    }
+}
+
+/// The loudest of `peak` and the combined gain of the enabled filters at the frequencies, at any
+/// of the sample rates, as (gain in dB, frequency).
+fn loudest(
+    filters: &Filters,
+    frequencies: &[f64],
+    sample_rates: &[u32],
+    mut peak: (f64, f64),
+) -> (f64, f64) {
+    for &sample_rate in sample_rates {
+        let sample_rate = f64::from(sample_rate);
+        let response = filters_response(filters, frequencies, sample_rate);
+        for (&frequency, &gain) in frequencies.iter().zip(&response.combined.magnitude_db) {
+            // Frequencies the stream can't carry can't clip
+            if frequency < sample_rate / 2.0 && gain > peak.0 {
+                peak = (gain, frequency);
+            }
+        }
+    }
+    peak
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::{AllpassFilter, LowpassFilter, PeakingFilter};
+    use std::f32::consts::FRAC_1_SQRT_2;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn peaking_filters_boost_their_centre_frequency() {
+        let mut filters = Filters::default();
+        filters.add(PeakingFilter::new(1000.0, 0.7, 6.0).unwrap().into(), true);
+        filters.add(PeakingFilter::new(1000.0, 0.7, 3.0).unwrap().into(), true);
+        let response = filters_response(&filters, &[1000.0, 1.0], 48000.0);
+
+        assert!(close(response.filters[0].magnitude_db[0], 6.0));
+        assert!(close(response.combined.magnitude_db[0], 9.0));
+        assert!(response.combined.magnitude_db[1].abs() < 0.01);
+        // A peaking filter has no phase shift at its centre frequency
+        assert!(response.combined.phase_degrees[0].abs() < 1e-6);
+    }
+
+    #[test]
+    fn disabled_filters_are_left_out_of_the_combined_response() {
+        let mut filters = Filters::default();
+        filters.add(PeakingFilter::new(1000.0, 0.7, 6.0).unwrap().into(), false);
+        let response = filters_response(&filters, &[1000.0], 48000.0);
+        assert!(close(response.filters[0].magnitude_db[0], 6.0));
+        assert!(close(response.combined.magnitude_db[0], 0.0));
+    }
+
+    #[test]
+    fn phase_and_group_delay_match_the_filter() {
+        let mut filters = Filters::default();
+        filters.add(
+            LowpassFilter::new(1000.0, FRAC_1_SQRT_2).unwrap().into(),
+            true,
+        );
+        filters.add(
+            AllpassFilter::new(1000.0, FRAC_1_SQRT_2).unwrap().into(),
+            false,
+        );
+        let response = filters_response(&filters, &[1000.0, 0.0], 48000.0);
+
+        // A second order lowpass is 3dB down with a 90 degree lag at its cutoff
+        assert!((response.filters[0].magnitude_db[0] + 3.01).abs() < 0.01);
+        assert!((response.filters[0].phase_degrees[0] + 90.0).abs() < 0.01);
+        // An allpass is flat, with a 180 degree shift at f0
+        assert!(close(response.filters[1].magnitude_db[0], 0.0));
+        assert!((response.filters[1].phase_degrees[0].abs() - 180.0).abs() < 0.01);
+
+        // The group delay of a Butterworth lowpass at DC is sqrt(2) / w0
+        let expected = 2f64.sqrt() / (2.0 * PI * 1000.0) * 1000.0;
+        assert!((response.filters[0].group_delay_ms[1] - expected).abs() < 0.001);
+    }
+
+    #[test]
+    fn poles_zeros_and_gain_are_found() {
+        let peaking = PeakingFilter::new(1000.0, 0.7, 6.0)
+            .unwrap()
+            .to_custom(48000.0);
+        let analysis = analyze(&peaking);
+        assert!(analysis.is_stable());
+        assert_eq!(analysis.poles.len(), 2);
+        assert_eq!(analysis.zeros.len(), 2);
+        assert!((analysis.max_gain_db - 6.0).abs() < 0.01);
+
+        // z^-1 - 1.5 z^-2 has a single finite zero, and poles at 0.5 and 2
+        let analysis = analyze(&CustomIIRFilter::new(1.0, -2.5, 1.0, 0.0, 1.0, -1.5));
+        assert_eq!(analysis.zeros, vec![Complex { re: 1.5, im: 0.0 }]);
+        assert_eq!(
+            analysis.poles,
+            vec![Complex { re: 2.0, im: 0.0 }, Complex { re: 0.5, im: 0.0 }]
+        );
+        assert!(!analysis.is_stable());
+        assert_eq!(analysis.poles[0].to_string(), "2.0000");
+    }
+
+    #[test]
+    fn the_preamp_cancels_the_loudest_boost() {
+        let mut filters = Filters::default();
+        filters.add(PeakingFilter::new(1000.0, 0.7, 6.0).unwrap().into(), true);
+        filters.add(PeakingFilter::new(5000.0, 2.0, 9.0).unwrap().into(), false);
+        filters.add(LowpassFilter::new(10000.0, 0.7).unwrap().into(), true);
+
+        let boosted = headroom(&filters, 1.5, 0.5, &[44100, 48000]);
+        assert!((boosted.peak_gain_db - 7.5).abs() < 0.05);
+        assert!((boosted.peak_frequency - 1000.0).abs() < 50.0);
+        assert!((boosted.preamp_db + 8.0).abs() < 0.05);
+
+        // A chain which only cuts just needs the margin, and the preamp never boosts
+        let mut filters = Filters::default();
+        filters.add(PeakingFilter::new(1000.0, 0.7, -6.0).unwrap().into(), true);
+        assert!((headroom(&filters, 0.0, 3.0, &[48000]).preamp_db + 3.0).abs() < 0.01);
+        assert_eq!(headroom(&filters, 0.0, -3.0, &[48000]).preamp_db, 0.0);
+    }
+
+    #[test]
+    fn narrow_peaks_are_found() {
+        // Far narrower than the grid, centred between two of its points
+        let mut filters = Filters::default();
+        filters.add(
+            PeakingFilter::new(1234.5, 100.0, 12.0).unwrap().into(),
+            true,
+        );
+        let found = headroom(&filters, 0.0, 0.0, &[48000]);
+        assert!((found.peak_gain_db - 12.0).abs() < 0.01);
+        assert_eq!(found.peak_frequency, 1234.5);
+
+        // Two overlapping peaks sum to more than either at its own f0
+        let mut filters = Filters::default();
+        filters.add(PeakingFilter::new(1000.0, 8.0, 6.0).unwrap().into(), true);
+        filters.add(PeakingFilter::new(1060.0, 8.0, 6.0).unwrap().into(), true);
+        let grid = headroom(&filters, 0.0, 0.0, &[48000]).peak_gain_db;
+        let frequencies: Vec<f64> = (0..=6000).map(|i| 1000.0 + f64::from(i) / 100.0).collect();
+        let exact = filters_response(&filters, &frequencies, 48000.0)
+            .combined
+            .magnitude_db
+            .into_iter()
+            .fold(f64::NEG_INFINITY, f64::max);
+        assert!((grid - exact).abs() < 0.01);
+    }
+
+    #[test]
+    fn responses_follow_the_sample_rate() {
+        let mut filters = Filters::default();
+        filters.add(PeakingFilter::new(1000.0, 0.7, 6.0).unwrap().into(), true);
+        for sample_rate in [44100.0, 48000.0, 96000.0] {
+            let response = filters_response(&filters, &[1000.0], sample_rate);
+            assert!(close(response.combined.magnitude_db[0], 6.0));
+        }
+    }
+}
@@ -0,0 +1,668 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use parking_lot::Mutex;
+
+use crate::{
+    capabilities::{FilterLimits, FirmwareCapabilities},
+    commands::{Chunk, Command, NokCode, StructureTypes},
+    error::Error,
+    tlv::TlvStructure,
+    transport::{Transport, MAX_CHUNKED_LEN},
+    Config, VersionInfo, MAX_CFG_LEN,
+};
+
+pub const SIMULATOR_SERIAL_NUMBER: &str = "SIMULATOR";
+pub const SIMULATOR_ENV: &str = "HEADPHONES_TOOLBOX_SIMULATOR";
+
+const CURRENT_VERSION: u16 = 4;
+const MINIMUM_SUPPORTED_VERSION: u16 = 4;
+const MAX_FILTERS: usize = 20;
+
+/// A software model of the firmware side of the configuration protocol. It answers the same
+/// commands as a real device, keeping separate active (RAM) and stored (flash) configurations.
+/// Clones share the same state, so the stored configuration survives reopening the device.
+#[derive(Debug, Clone)]
+pub struct SimulatedDevice(Arc<Mutex<SimulatorState>>);
+
+#[derive(Debug)]
+struct SimulatorState {
+    active: Vec<u8>,
+    stored: Vec<u8>,
+    chunk_len: Option<usize>,
+    received: Vec<u8>,
+    pending: VecDeque<Vec<u8>>,
+    unplugged: bool,
+}
+
+impl SimulatedDevice {
+    pub fn new() -> Self {
+        Self::with_chunk_len(Some(MAX_CFG_LEN))
+    }
+
+    /// A simulator of a firmware which predates chunked transfers.
+    #[cfg(test)]
+    pub fn without_chunking() -> Self {
+        Self::with_chunk_len(None)
+    }
+
+    fn with_chunk_len(chunk_len: Option<usize>) -> Self {
+        let defaults = default_config();
+        Self(Arc::new(Mutex::new(SimulatorState {
+            active: defaults.clone(),
+            stored: defaults,
+            chunk_len,
+            received: Vec::new(),
+            pending: VecDeque::new(),
+            unplugged: false,
+        })))
+    }
+
+    /// Simulates pulling the cable, the device loses its active configuration.
+    #[cfg(test)]
+    pub fn unplug(&self) {
+        let mut state = self.0.lock();
+        state.unplugged = true;
+        state.active = state.stored.clone();
+    }
+
+    #[cfg(test)]
+    pub fn replug(&self) {
+        self.0.lock().unplugged = false;
+    }
+
+    /// Returns a simulator if one was requested through the environment.
+    pub fn from_env() -> Option<Self> {
+        std::env::var_os(SIMULATOR_ENV).map(|_| Self::new())
+    }
+}
+
+impl Default for SimulatedDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn tlv(type_val: u16, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + payload.len());
+    buf.extend_from_slice(&type_val.to_le_bytes());
+    buf.extend_from_slice(&((4 + payload.len()) as u16).to_le_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+fn default_config() -> Vec<u8> {
+    let mut buf = Vec::new();
+    Config::default().encode(&mut buf);
+    buf
+}
+
+fn version_status(chunk_len: Option<usize>) -> Vec<u8> {
+    let mut status = Vec::new();
+    VersionInfo {
+        current_version: CURRENT_VERSION,
+        minimum_supported_version: MINIMUM_SUPPORTED_VERSION,
+        git_hash: "simulator".to_owned(),
+        pico_sdk_version: "0.0.0".to_owned(),
+        capabilities: Some(FirmwareCapabilities {
+            max_filters: MAX_FILTERS as u16,
+            per_channel_eq: false,
+            codec: true,
+            filter_types: (0..=9).collect(),
+            filter_limits: FilterLimits::default(),
+            sample_rates: vec![48000],
+        }),
+    }
+    .encode(&mut status);
+
+    if let Some(chunk_len) = chunk_len {
+        let mut capabilities = Vec::new();
+        capabilities.extend_from_slice(&(chunk_len as u16).to_le_bytes());
+        capabilities.extend_from_slice(&[0u8; 2]); // reserved bytes
+        capabilities.extend_from_slice(&(MAX_CHUNKED_LEN as u32).to_le_bytes());
+        status.extend(tlv(
+            StructureTypes::TransferCapabilities as u16,
+            &capabilities,
+        ));
+    }
+    status
+}
+
+/// Splits a buffer into (type, value) pairs, failing if any length is inconsistent.
+fn split_tlvs(mut buf: &[u8]) -> Option<Vec<(u16, &[u8])>> {
+    let mut tlvs = Vec::new();
+    while !buf.is_empty() {
+        if buf.len() < 4 {
+            return None;
+        }
+        let type_val = u16::from_le_bytes([buf[0], buf[1]]);
+        let length = usize::from(u16::from_le_bytes([buf[2], buf[3]]));
+        if length < 4 || length > buf.len() {
+            return None;
+        }
+        tlvs.push((type_val, &buf[4..length]));
+        buf = &buf[length..];
+    }
+    Some(tlvs)
+}
+
+/// Builds a Nok response with an ErrorStatus naming the offending TLV, 0 if there isn't one. The
+/// reference firmware sends an empty Nok, the details only help the simulator's users.
+fn nok(code: NokCode, tlv_type: u16) -> Vec<u8> {
+    let mut status = Vec::new();
+    status.extend_from_slice(&(code as u16).to_le_bytes());
+    status.extend_from_slice(&tlv_type.to_le_bytes());
+    tlv(
+        StructureTypes::Nok as u16,
+        &tlv(StructureTypes::ErrorStatus as u16, &status),
+    )
+}
+
+/// Checks that a filter TLV value is a sequence of well formed filters the firmware can run.
+fn validate_filters(mut buf: &[u8]) -> Result<(), NokCode> {
+    let mut count = 0;
+    while !buf.is_empty() {
+        let size = match buf[0] {
+            0..=5 => 4 + 8,  // Frequency and quality
+            6..=8 => 4 + 12, // Frequency, gain and quality
+            9 => 4 + 48,     // Custom IIR, six f64 coefficients
+            _ => return Err(NokCode::InvalidValue),
+        };
+        if buf.len() < size {
+            return Err(NokCode::InvalidLength);
+        }
+        buf = &buf[size..];
+        count += 1;
+    }
+    if count > MAX_FILTERS {
+        return Err(NokCode::LimitExceeded);
+    }
+    Ok(())
+}
+
+/// Replaces the TLVs in `config` with the ones in `update`, keeping the order stable.
+fn merge_config(config: &[u8], update: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut merged: Vec<(u16, Vec<u8>)> = split_tlvs(config)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(t, v)| (t, v.to_vec()))
+        .collect();
+    for (type_val, value) in update {
+        match merged.iter_mut().find(|(t, _)| t == type_val) {
+            Some(existing) => existing.1 = value.to_vec(),
+            None => merged.push((*type_val, value.to_vec())),
+        }
+    }
+    merged.iter().flat_map(|(t, v)| tlv(*t, v)).collect()
+}
+
+impl SimulatorState {
+    fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let ok = |payload: &[u8]| tlv(StructureTypes::Ok as u16, payload);
+
+        let (type_val, value) = match split_tlvs(request).as_deref() {
+            Some([single]) => *single,
+            _ => return nok(NokCode::InvalidLength, 0),
+        };
+
+        match type_val {
+            x if x == StructureTypes::GetVersion as u16 => ok(&version_status(self.chunk_len)),
+            x if x == StructureTypes::SetConfiguration as u16 => {
+                let tlvs = match split_tlvs(value) {
+                    Some(x) => x,
+                    None => return nok(NokCode::InvalidLength, type_val),
+                };
+                for (t, v) in &tlvs {
+                    let length_matches = |len| {
+                        if v.len() == len {
+                            Ok(())
+                        } else {
+                            Err(NokCode::InvalidLength)
+                        }
+                    };
+                    let result = match *t {
+                        x if x == StructureTypes::PreProcessingConfiguration as u16 => {
+                            length_matches(12)
+                        }
+                        x if x == StructureTypes::FilterConfiguration as u16 => validate_filters(v),
+                        x if x == StructureTypes::Pcm3060Configuration as u16 => length_matches(4),
+                        _ => Err(NokCode::UnsupportedStructure),
+                    };
+                    if let Err(code) = result {
+                        return nok(code, *t);
+                    }
+                }
+                self.active = merge_config(&self.active, &tlvs);
+                ok(&[])
+            }
+            x if x == StructureTypes::GetActiveConfiguration as u16 => ok(&self.active),
+            x if x == StructureTypes::GetStoredConfiguration as u16 => ok(&self.stored),
+            x if x == StructureTypes::SaveConfiguration as u16 => {
+                self.stored = self.active.clone();
+                ok(&[])
+            }
+            x if x == StructureTypes::FactoryReset as u16 => {
+                self.stored = default_config();
+                ok(&[])
+            }
+            _ => nok(NokCode::UnknownCommand, type_val),
+        }
+    }
+
+    /// Accepts one frame from the host, returning the complete request once every chunk of it
+    /// has arrived. Errors name the TLV at fault, the frame itself or the chunk it belongs to.
+    fn receive(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, (NokCode, u16)> {
+        let frame_type = match frame {
+            [a, b, ..] => u16::from_le_bytes([*a, *b]),
+            _ => 0,
+        };
+        if frame_type != StructureTypes::Chunk as u16 {
+            self.received.clear();
+            if frame.len() > MAX_CFG_LEN {
+                return Err((NokCode::LimitExceeded, frame_type));
+            }
+            return Ok(Some(frame.to_vec()));
+        }
+        self.receive_chunk(frame)
+            .map_err(|code| (code, StructureTypes::Chunk as u16))
+    }
+
+    fn receive_chunk(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, NokCode> {
+        if self.chunk_len.is_none() {
+            return Err(NokCode::UnknownCommand);
+        }
+        if frame.len() < Chunk::HEADER_LEN {
+            return Err(NokCode::InvalidLength);
+        }
+        let sequence = usize::from(u16::from_le_bytes([frame[4], frame[5]]));
+        let total = u32::from_le_bytes([frame[8], frame[9], frame[10], frame[11]]) as usize;
+        let chunk_data_len = self.chunk_len.unwrap() - Chunk::HEADER_LEN;
+        if sequence == 0 {
+            self.received.clear();
+        }
+        if self.received.len() != sequence * chunk_data_len || total > MAX_CHUNKED_LEN {
+            self.received.clear();
+            return Err(NokCode::InvalidValue);
+        }
+        self.received.extend_from_slice(&frame[Chunk::HEADER_LEN..]);
+        match self.received.len() {
+            x if x < total => Ok(None),
+            x if x == total => Ok(Some(std::mem::take(&mut self.received))),
+            _ => {
+                self.received.clear();
+                Err(NokCode::InvalidLength)
+            }
+        }
+    }
+
+    /// Queues a response, splitting it into chunks if it doesn't fit in a single frame.
+    fn respond(&mut self, response: Vec<u8>) {
+        self.pending.clear();
+        match self.chunk_len {
+            Some(chunk_len) if response.len() > chunk_len => {
+                for (sequence, data) in response.chunks(chunk_len - Chunk::HEADER_LEN).enumerate() {
+                    let mut frame = Vec::new();
+                    Chunk::new(sequence as u16, response.len() as u32, data)
+                        .write_as_binary(&mut frame);
+                    self.pending.push_back(frame);
+                }
+            }
+            _ => self.pending.push_back(response),
+        }
+    }
+}
+
+impl Transport for SimulatedDevice {
+    fn write_frame(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut state = self.0.lock();
+        match state.receive(buf) {
+            Ok(Some(request)) => {
+                let response = state.handle(&request);
+                state.respond(response);
+            }
+            Ok(None) => (),
+            Err((code, tlv_type)) => state.respond(nok(code, tlv_type)),
+        }
+        Ok(buf.len())
+    }
+
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut state = self.0.lock();
+        let frame = match state.pending.front_mut() {
+            Some(x) => x,
+            None => {
+                return Err(Error::usb(
+                    "Error reading from the configuration inteface",
+                    rusb::Error::Timeout,
+                ))
+            }
+        };
+        let len = frame.len().min(buf.len());
+        buf[..len].copy_from_slice(&frame[..len]);
+        frame.drain(..len);
+        if frame.is_empty() {
+            state.pending.pop_front();
+        }
+        Ok(len)
+    }
+
+    fn write_control(
+        &mut self,
+        _request_type: u8,
+        _request: u8,
+        _value: u16,
+        _index: u16,
+        buf: &[u8],
+    ) -> Result<usize, Error> {
+        Ok(buf.len())
+    }
+
+    fn is_connected(&self) -> bool {
+        !self.0.lock().unplugged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filters::{CustomIIRFilter, Filters, PeakingFilter},
+        test_config,
+        tlv::encode_tlv,
+        Codec, ConfigDiff, ConnectionState, Preprocessing,
+    };
+
+    fn connect(simulator: &SimulatedDevice) -> ConnectionState {
+        ConnectionState::connected_to(simulator.clone())
+    }
+
+    /// A configuration with `filter_count` enabled filters, 100 Hz apart.
+    fn with_filters(filter_count: usize) -> Config {
+        test_config((1..=filter_count).map(|i| (100.0 * i as f32, true)))
+    }
+
+    fn as_json(config: &Config) -> serde_json::Value {
+        serde_json::to_value(config).unwrap()
+    }
+
+    fn open(simulator: &SimulatedDevice) -> ConnectionState {
+        let mut connection = ConnectionState {
+            simulator: Some(simulator.clone()),
+            ..Default::default()
+        };
+        connection.open(SIMULATOR_SERIAL_NUMBER).unwrap();
+        connection
+    }
+
+    fn request(simulator: &mut SimulatedDevice, cmd: &[u8]) -> Vec<u8> {
+        simulator.write_frame(cmd).unwrap();
+        let mut buf = [0u8; 512];
+        let len = simulator.read_frame(&mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn version_works() {
+        let simulator = SimulatedDevice::new();
+        let info = connect(&simulator).read_version_info().unwrap();
+        assert_eq!(info.current_version, CURRENT_VERSION);
+        assert_eq!(info.minimum_supported_version, MINIMUM_SUPPORTED_VERSION);
+        assert_eq!(info.git_hash, "simulator");
+    }
+
+    #[test]
+    fn write_does_not_persist_until_saved() {
+        let simulator = SimulatedDevice::new();
+        let mut connection = connect(&simulator);
+        let config = with_filters(3);
+
+        connection.write_config(&config).unwrap();
+        assert_eq!(
+            as_json(&connection.load_config().unwrap()),
+            as_json(&Config::default())
+        );
+
+        connection.save_config().unwrap();
+        assert_eq!(
+            as_json(&connection.load_config().unwrap()),
+            as_json(&config)
+        );
+
+        // The stored configuration survives reopening the device
+        let mut reopened = connect(&simulator);
+        assert_eq!(as_json(&reopened.load_config().unwrap()), as_json(&config));
+    }
+
+    #[test]
+    fn unsaved_changes_are_reported() {
+        let simulator = SimulatedDevice::new();
+        let mut connection = connect(&simulator);
+        let config = with_filters(3);
+        assert_eq!(connection.config_diff().unwrap(), ConfigDiff::default());
+
+        connection.write_config(&config).unwrap();
+        assert_eq!(
+            as_json(&connection.load_active_config().unwrap()),
+            as_json(&config)
+        );
+        assert!(connection.config_diff().unwrap().filters);
+
+        connection.save_config().unwrap();
+        assert_eq!(connection.config_diff().unwrap(), ConfigDiff::default());
+    }
+
+    #[test]
+    fn factory_reset_clears_flash() {
+        let simulator = SimulatedDevice::new();
+        let mut connection = connect(&simulator);
+        connection.write_config(&with_filters(2)).unwrap();
+        connection.save_config().unwrap();
+        connection.factory_reset().unwrap();
+        assert_eq!(
+            as_json(&connection.load_config().unwrap()),
+            as_json(&Config::default())
+        );
+    }
+
+    #[test]
+    fn verified_writes_succeed() {
+        let simulator = SimulatedDevice::new();
+        let mut connection = connect(&simulator);
+        connection.verify_writes = true;
+        connection.write_config(&with_filters(5)).unwrap();
+        connection.save_config().unwrap();
+    }
+
+    #[test]
+    fn active_configuration_tracks_writes() {
+        let mut simulator = SimulatedDevice::new();
+        let config = with_filters(1);
+        connect(&simulator).write_config(&config).unwrap();
+
+        let response = request(&mut simulator, &[5, 0, 4, 0]);
+        let mut expected = Vec::new();
+        encode_tlv(&mut expected, StructureTypes::Ok as u16, |buf| {
+            config.encode(buf)
+        });
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        let mut simulator = SimulatedDevice::new();
+        // Unknown command
+        assert_eq!(
+            request(&mut simulator, &[0x10, 0, 4, 0]),
+            nok(NokCode::UnknownCommand, 0x10)
+        );
+        // Length longer than the frame
+        assert_eq!(
+            request(&mut simulator, &[3, 0, 8, 0]),
+            nok(NokCode::InvalidLength, 0)
+        );
+        // Unknown configuration structure
+        assert_eq!(
+            request(&mut simulator, &tlv(4, &tlv(0x2ff, &[0u8; 4]))),
+            nok(NokCode::UnsupportedStructure, 0x2ff)
+        );
+        // Unknown filter type
+        let filters = tlv(
+            StructureTypes::FilterConfiguration as u16,
+            &[42, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        );
+        assert_eq!(
+            request(&mut simulator, &tlv(4, &filters)),
+            nok(NokCode::InvalidValue, 0x201)
+        );
+        // Too long for a single frame, the request itself is at fault rather than a chunk
+        let long = tlv(4, &tlv(0x2ff, &[0u8; MAX_CFG_LEN]));
+        assert_eq!(
+            request(&mut simulator, &long),
+            nok(NokCode::LimitExceeded, 4)
+        );
+    }
+
+    #[test]
+    fn rejects_too_many_filters() {
+        let simulator = SimulatedDevice::new();
+        let mut connection = connect(&simulator);
+        match connection.write_config(&with_filters(MAX_FILTERS + 1)) {
+            Err(Error::Nok { code, tlv_type, .. }) => {
+                assert_eq!(code, NokCode::LimitExceeded as u16);
+                assert_eq!(tlv_type, Some(StructureTypes::FilterConfiguration as u16));
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        connection.save_config().unwrap();
+        assert_eq!(
+            as_json(&connection.load_config().unwrap()),
+            as_json(&Config::default())
+        );
+    }
+
+    #[test]
+    fn negotiated_limits_are_checked_before_sending() {
+        let simulator = SimulatedDevice::new();
+        let mut connection = connect(&simulator);
+        connection.negotiate().unwrap();
+        assert_eq!(
+            connection.capabilities().unwrap().max_filters,
+            Some(MAX_FILTERS as u16)
+        );
+        assert!(matches!(
+            connection.write_config(&with_filters(MAX_FILTERS + 1)),
+            Err(Error::Validation(_))
+        ));
+        connection.write_config(&with_filters(MAX_FILTERS)).unwrap();
+    }
+
+    fn long_config() -> Config {
+        let mut config = with_filters(0);
+        for i in 0..MAX_FILTERS {
+            let b0 = 1.0 + i as f64 / 100.0;
+            let filter = CustomIIRFilter::new(1.0, -1.8, 0.81, b0, -1.8, 0.81);
+            config.filters.add(filter.into(), true);
+        }
+        config
+    }
+
+    #[test]
+    fn long_configs_use_chunks() {
+        let simulator = SimulatedDevice::new();
+        let mut connection = connect(&simulator);
+        connection.negotiate().unwrap();
+        let config = long_config();
+        let mut value = Vec::new();
+        config.filters.encode_value(&mut value);
+        assert!(value.len() > MAX_CFG_LEN);
+
+        connection.write_config(&config).unwrap();
+        connection.save_config().unwrap();
+        assert_eq!(
+            as_json(&connection.load_config().unwrap()),
+            as_json(&config)
+        );
+    }
+
+    #[test]
+    fn long_configs_are_not_truncated_without_chunks() {
+        let simulator = SimulatedDevice::without_chunking();
+        let mut connection = connect(&simulator);
+        connection.negotiate().unwrap();
+        assert!(connection.write_config(&long_config()).is_err());
+
+        // Configs that fit in a single frame still work
+        let config = with_filters(3);
+        connection.write_config(&config).unwrap();
+        connection.save_config().unwrap();
+        assert_eq!(
+            as_json(&connection.load_config().unwrap()),
+            as_json(&config)
+        );
+    }
+
+    #[test]
+    fn replugged_devices_are_reconnected() {
+        let simulator = SimulatedDevice::new();
+        let mut connection = open(&simulator);
+        connection.write_config(&with_filters(2)).unwrap();
+
+        simulator.unplug();
+        assert!(!connection.check_connection());
+        assert!(matches!(connection.load_config(), Err(Error::NotConnected)));
+
+        simulator.replug();
+        assert!(connection.check_connection());
+        // The written configuration was lost with the power
+        assert_eq!(
+            as_json(&connection.load_active_config().unwrap()),
+            as_json(&Config::default())
+        );
+    }
+
+    #[test]
+    fn reconnecting_can_restore_the_last_config() {
+        let simulator = SimulatedDevice::new();
+        let mut connection = open(&simulator);
+        connection.restore_config = true;
+        let config = with_filters(2);
+        connection.write_config(&config).unwrap();
+
+        simulator.unplug();
+        assert!(!connection.check_connection());
+        simulator.replug();
+        assert!(connection.check_connection());
+        assert_eq!(
+            as_json(&connection.load_active_config().unwrap()),
+            as_json(&config)
+        );
+
+        // A device which kept its configuration is left alone
+        connection.write_config(&with_filters(3)).unwrap();
+        connection.devices.clear();
+        assert!(connection.check_connection());
+        assert_eq!(
+            as_json(&connection.load_active_config().unwrap()),
+            as_json(&with_filters(3))
+        );
+    }
+
+    #[test]
+    fn the_preamp_can_be_set_automatically() {
+        let simulator = SimulatedDevice::new();
+        let mut connection = open(&simulator);
+        connection.verify_writes = true;
+        connection.auto_preamp = Some(1.0);
+        let mut filters = Filters::default();
+        filters.add(PeakingFilter::new(1000.0, 0.7, 6.0).unwrap().into(), true);
+        let config = Config::new(
+            Preprocessing::new(1.0, 1.0, false),
+            filters,
+            Codec::default(),
+        );
+        let preamp = connection.write_config(&config).unwrap().preamp.unwrap();
+        assert!((preamp + 7.0).abs() < 0.05);
+
+        let applied = connection.load_active_config().unwrap();
+        assert!((applied.preprocessing.preamp - preamp).abs() < 0.05);
+    }
+}
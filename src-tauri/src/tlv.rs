@@ -0,0 +1,234 @@
+use crate::error::Error;
+
+/// A bounds-checked reader over little endian TLV structures. Every read returns an error rather
+/// than panicking when the buffer is shorter than a buggy firmware claimed it was.
+#[derive(Debug, Clone)]
+pub struct TlvReader<'a> {
+    buf: &'a [u8],
+    position: usize,
+}
+
+impl<'a> TlvReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, position: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.position
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.remaining() {
+            return Err(Error::Protocol(format!(
+                "Truncated response, wanted {} bytes at offset {} but only {} remain",
+                len,
+                self.position,
+                self.remaining()
+            )));
+        }
+        let bytes = &self.buf[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    /// Checks that exactly `len` bytes remain, for fixed size values which shouldn't have anything
+    /// left over or missing.
+    pub fn expect_len(&self, len: usize) -> Result<(), Error> {
+        if self.remaining() != len {
+            return Err(Error::Protocol(format!(
+                "Expected a value of {} bytes but found {}",
+                len,
+                self.remaining()
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn skip(&mut self, len: usize) -> Result<(), Error> {
+        self.read_bytes(len).map(|_| ())
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.read_bytes(N)?);
+        Ok(array)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, Error> {
+        Ok(f32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64, Error> {
+        Ok(f64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, Error> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    /// Reads a NUL terminated UTF-8 string.
+    pub fn read_c_string(&mut self) -> Result<String, Error> {
+        let rest = &self.buf[self.position..];
+        let len = rest.iter().position(|&c| c == 0).ok_or_else(|| {
+            Error::Protocol(format!("Unterminated string at offset {}", self.position))
+        })?;
+        let s = std::str::from_utf8(&rest[..len])
+            .map_err(|e| Error::Protocol(format!("Invalid UTF-8 sequence: {}", e)))?;
+        self.position += len + 1;
+        Ok(s.to_owned())
+    }
+
+    /// Reads a TLV header and returns its type along with a reader over just its value. The
+    /// declared length must fit inside the remaining buffer.
+    pub fn read_tlv(&mut self) -> Result<(u16, TlvReader<'a>), Error> {
+        let type_val = self.read_u16()?;
+        let length = usize::from(self.read_u16()?);
+        if length < 4 {
+            return Err(Error::Protocol(format!(
+                "TLV type {:#x} has an invalid length of {} bytes",
+                type_val, length
+            )));
+        }
+        if length - 4 > self.remaining() {
+            return Err(Error::Protocol(format!(
+                "TLV type {:#x} claims {} bytes but only {} remain",
+                type_val,
+                length,
+                self.remaining() + 4
+            )));
+        }
+        Ok((type_val, TlvReader::new(self.read_bytes(length - 4)?)))
+    }
+}
+
+/// Appends a TLV to `buf`, the length is filled in once `write_value` has written the value. A
+/// value too long for the 16 bit length is given the largest length instead of wrapping around,
+/// so the frame is always too long for `write_request` to send rather than silently corrupt.
+pub fn encode_tlv(buf: &mut Vec<u8>, type_val: u16, write_value: impl FnOnce(&mut Vec<u8>)) {
+    let start = buf.len();
+    buf.extend_from_slice(&type_val.to_le_bytes());
+    buf.extend_from_slice(&[0u8; 2]);
+    write_value(buf);
+    let length = u16::try_from(buf.len() - start).unwrap_or(u16::MAX);
+    buf[start + 2..start + 4].copy_from_slice(&length.to_le_bytes());
+}
+
+/// A structure sent to or read from the device as a single TLV. Implementations provide both
+/// directions of the wire format, `decode_value` must accept anything `encode_value` writes.
+pub trait TlvStructure: Sized {
+    const TYPE: u16;
+
+    fn encode_value(&self, buf: &mut Vec<u8>);
+    fn decode_value(cur: &mut TlvReader) -> Result<Self, Error>;
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        encode_tlv(buf, Self::TYPE, |buf| self.encode_value(buf));
+    }
+
+    fn decode(cur: &mut TlvReader) -> Result<Self, Error> {
+        let (type_val, mut value) = cur.read_tlv()?;
+        if type_val != Self::TYPE {
+            return Err(Error::Protocol(format!(
+                "Expected TLV type {:#x} but found {:#x}",
+                Self::TYPE,
+                type_val
+            )));
+        }
+        Self::decode_value(&mut value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_values() {
+        let mut buf = vec![1u8];
+        buf.extend_from_slice(&0x1234u16.to_le_bytes());
+        buf.extend_from_slice(&0x12345678u32.to_le_bytes());
+        buf.extend_from_slice(&1.5f32.to_le_bytes());
+        buf.extend_from_slice(&(-2.25f64).to_le_bytes());
+        buf.extend_from_slice(b"hash\0");
+
+        let mut reader = TlvReader::new(&buf);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_u16().unwrap(), 0x1234);
+        assert_eq!(reader.read_u32().unwrap(), 0x12345678);
+        assert_eq!(reader.read_f32().unwrap(), 1.5);
+        assert_eq!(reader.read_f64().unwrap(), -2.25);
+        assert_eq!(reader.read_c_string().unwrap(), "hash");
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn truncation_is_an_error() {
+        let mut reader = TlvReader::new(&[1, 2, 3]);
+        assert!(reader.read_u32().is_err());
+        // A failed read doesn't consume anything
+        assert_eq!(reader.read_u16().unwrap(), 0x201);
+        assert!(reader.read_f64().is_err());
+        assert_eq!(reader.remaining(), 1);
+
+        assert!(TlvReader::new(b"abc").read_c_string().is_err());
+        assert!(TlvReader::new(&[1, 2, 3]).expect_len(2).is_err());
+        assert!(TlvReader::new(&[1, 2, 3]).expect_len(3).is_ok());
+        assert!(TlvReader::new(&[0xff, 0xfe, 0]).read_c_string().is_err());
+    }
+
+    #[test]
+    fn encodes_lengths() {
+        let mut buf = Vec::new();
+        encode_tlv(&mut buf, 0x4, |buf| {
+            encode_tlv(buf, 0x200, |buf| buf.extend_from_slice(&[1, 2, 3, 4]));
+            encode_tlv(buf, 0x201, |_| ());
+        });
+        assert_eq!(
+            buf,
+            [0x4, 0x0, 16, 0, 0x0, 0x2, 8, 0, 1, 2, 3, 4, 0x1, 0x2, 4, 0]
+        );
+
+        // Too long for the length field
+        let mut buf = Vec::new();
+        encode_tlv(&mut buf, 0x4, |buf| buf.resize(buf.len() + 70000, 0));
+        assert_eq!(buf.len(), 70004);
+        assert_eq!(buf[2..4], u16::MAX.to_le_bytes());
+    }
+
+    #[test]
+    fn nested_tlvs_are_bounded() {
+        let buf = [0x0, 0x2, 8, 0, 1, 2, 3, 4, 0x1, 0x2, 4, 0];
+        let mut reader = TlvReader::new(&buf);
+        let (type_val, mut value) = reader.read_tlv().unwrap();
+        assert_eq!(type_val, 0x200);
+        assert_eq!(value.read_u32().unwrap(), 0x04030201);
+        assert!(value.read_u8().is_err());
+        let (type_val, value) = reader.read_tlv().unwrap();
+        assert_eq!(type_val, 0x201);
+        assert!(value.is_empty());
+        assert!(reader.read_tlv().is_err());
+
+        // Longer than the buffer
+        assert!(TlvReader::new(&[0x0, 0x2, 9, 0, 1, 2, 3, 4])
+            .read_tlv()
+            .is_err());
+        // Shorter than its own header
+        assert!(TlvReader::new(&[0x0, 0x2, 3, 0]).read_tlv().is_err());
+    }
+}
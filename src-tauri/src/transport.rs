@@ -0,0 +1,594 @@
+use std::fmt::Debug;
+
+use rusb::DeviceHandle;
+
+use crate::{
+    commands::{Chunk, Command, StructureTypes},
+    error::Error,
+    tlv::TlvReader,
+    ConfigurationInterface, MAX_CFG_LEN, USB_TIMEOUT,
+};
+
+/// The largest frame we will reassemble from chunks. Chunks carry a 32 bit total length, but the
+/// reassembled frame is still a TLV whose length field is only 16 bits. That is enough for over a
+/// thousand filters of the largest type (a custom IIR is 52 bytes), far more than any firmware
+/// can run, so the TLV format is left alone rather than adding a second, wider length encoding.
+pub const MAX_CHUNKED_LEN: usize = u16::MAX as usize;
+
+/// The raw link to a device. Frames are written to the configuration interface and responses are
+/// read back one packet at a time, the caller is responsible for reassembling them.
+pub trait Transport: Debug + Send {
+    fn write_frame(&mut self, buf: &[u8]) -> Result<usize, Error>;
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, Error>;
+    fn write_control(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+    ) -> Result<usize, Error>;
+    fn is_connected(&self) -> bool;
+}
+
+#[derive(Debug)]
+pub struct RusbTransport {
+    device_handle: DeviceHandle<rusb::Context>,
+    configuration_interface: ConfigurationInterface,
+}
+
+impl RusbTransport {
+    pub fn new(
+        device_handle: DeviceHandle<rusb::Context>,
+        configuration_interface: ConfigurationInterface,
+    ) -> Self {
+        Self {
+            device_handle,
+            configuration_interface,
+        }
+    }
+}
+
+impl Transport for RusbTransport {
+    fn write_frame(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.device_handle
+            .write_bulk(self.configuration_interface.output, buf, USB_TIMEOUT)
+            .map_err(|e| Error::usb("Failed to write to the configuration interface", e))
+    }
+
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.device_handle
+            .read_bulk(self.configuration_interface.input, buf, USB_TIMEOUT)
+            .map_err(|e| Error::usb("Error reading from the configuration inteface", e))
+    }
+
+    fn write_control(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+    ) -> Result<usize, Error> {
+        self.device_handle
+            .write_control(request_type, request, value, index, buf, USB_TIMEOUT)
+            .map_err(|e| Error::usb("Control transfer failed", e))
+    }
+
+    fn is_connected(&self) -> bool {
+        self.device_handle.active_configuration().is_ok()
+    }
+}
+
+/// How frames larger than a single transfer are exchanged with the device.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum TransferMode {
+    /// Every request and response fits in one frame of at most `MAX_CFG_LEN` bytes.
+    #[default]
+    SingleFrame,
+    /// Large frames are split into `Chunk` frames of at most `max_chunk_len` bytes each.
+    Chunked {
+        max_chunk_len: usize,
+        max_transfer_len: usize,
+    },
+}
+
+impl TransferMode {
+    /// Picks the transfer mode from a GetVersion response. Firmwares which support chunked
+    /// transfers include a TransferCapabilities TLV next to the VersionStatus.
+    pub fn from_version_response(buf: &[u8]) -> Self {
+        let mut body = TlvReader::new(buf.get(4..).unwrap_or_default());
+        while let Ok((type_val, mut value)) = body.read_tlv() {
+            if type_val != StructureTypes::TransferCapabilities as u16 {
+                continue;
+            }
+            let (Ok(max_chunk_len), Ok(_), Ok(max_transfer_len)) =
+                (value.read_u16(), value.read_u16(), value.read_u32())
+            else {
+                break;
+            };
+            let max_chunk_len = usize::from(max_chunk_len);
+            if max_chunk_len > Chunk::HEADER_LEN {
+                return TransferMode::Chunked {
+                    max_chunk_len: max_chunk_len.min(MAX_CFG_LEN),
+                    max_transfer_len: (max_transfer_len as usize).min(MAX_CHUNKED_LEN),
+                };
+            }
+        }
+        TransferMode::SingleFrame
+    }
+}
+
+/// Writes a request, splitting it into chunks if it is too large for a single frame.
+pub fn write_request(
+    transport: &mut dyn Transport,
+    buf: &[u8],
+    mode: TransferMode,
+) -> Result<(), Error> {
+    let (max_chunk_len, max_transfer_len) = match mode {
+        TransferMode::SingleFrame => (MAX_CFG_LEN, MAX_CFG_LEN),
+        TransferMode::Chunked {
+            max_chunk_len,
+            max_transfer_len,
+        } => (max_chunk_len, max_transfer_len),
+    };
+
+    if buf.len() > max_transfer_len {
+        return Err(Error::Validation(format!(
+            "The request is {} bytes, but the device accepts at most {} bytes.",
+            buf.len(),
+            max_transfer_len
+        )));
+    }
+
+    if buf.len() <= max_chunk_len {
+        transport.write_frame(buf)?;
+        return Ok(());
+    }
+
+    for (sequence, data) in buf.chunks(max_chunk_len - Chunk::HEADER_LEN).enumerate() {
+        let mut frame = Vec::new();
+        Chunk::new(sequence as u16, buf.len() as u32, data).write_as_binary(&mut frame);
+        transport.write_frame(&frame)?;
+    }
+    Ok(())
+}
+
+/// Reads a complete response. Chunked responses are reassembled into the original frame, anything
+/// else is returned as is.
+pub fn read_response(transport: &mut dyn Transport) -> Result<Vec<u8>, Error> {
+    let frame = read_single_frame(transport)?;
+    if u16::from_le_bytes([frame[0], frame[1]]) != StructureTypes::Chunk as u16 {
+        return Ok(frame);
+    }
+
+    let mut response = Vec::new();
+    let mut frame = frame;
+    let mut expected_sequence: u16 = 0;
+    let mut total_length = None;
+    loop {
+        if frame.len() < Chunk::HEADER_LEN
+            || u16::from_le_bytes([frame[0], frame[1]]) != StructureTypes::Chunk as u16
+        {
+            return Err(Error::Protocol(
+                "Expected a chunk from the configuration interface.".to_owned(),
+            ));
+        }
+        let sequence = u16::from_le_bytes([frame[4], frame[5]]);
+        let total = u32::from_le_bytes([frame[8], frame[9], frame[10], frame[11]]) as usize;
+        if sequence != expected_sequence {
+            return Err(Error::Protocol(format!(
+                "Received chunk {}, expected chunk {}.",
+                sequence, expected_sequence
+            )));
+        }
+        if total < 4 {
+            return Err(Error::Protocol(format!(
+                "Invalid chunked response length {}, the header alone is 4 bytes.",
+                total
+            )));
+        }
+        if total > MAX_CHUNKED_LEN {
+            return Err(Error::Protocol(format!(
+                "Overflow reading from the config interface, got {} bytes, max size is {} bytes.",
+                total, MAX_CHUNKED_LEN
+            )));
+        }
+        if *total_length.get_or_insert(total) != total {
+            return Err(Error::Protocol(
+                "The total length changed between chunks.".to_owned(),
+            ));
+        }
+
+        response.extend_from_slice(&frame[Chunk::HEADER_LEN..]);
+        if response.len() > total {
+            return Err(Error::Protocol(format!(
+                "Received {} bytes in chunks, but the response is {} bytes.",
+                response.len(),
+                total
+            )));
+        }
+        if response.len() == total {
+            let length = usize::from(u16::from_le_bytes([response[2], response[3]]));
+            if length != total {
+                return Err(Error::Protocol(format!(
+                    "The reassembled response claims {} bytes, but the chunks carried {} bytes.",
+                    length, total
+                )));
+            }
+            return Ok(response);
+        }
+
+        expected_sequence = expected_sequence.wrapping_add(1);
+        frame = read_single_frame(transport)?;
+    }
+}
+
+/// Reads a complete response, turning a NOK into an error. The whole OK frame is returned.
+pub fn read_ok_response(transport: &mut dyn Transport) -> Result<Vec<u8>, Error> {
+    let response = read_response(transport)?;
+    let (type_val, mut value) = TlvReader::new(&response).read_tlv()?;
+    match type_val {
+        x if x == StructureTypes::Ok as u16 => Ok(response),
+        x if x == StructureTypes::Nok as u16 => {
+            Err(Error::from_nok(value.read_bytes(value.remaining())?))
+        }
+        x => Err(Error::Protocol(format!("Unexpected response type {}", x))),
+    }
+}
+
+/// Reads one frame. A frame may be split across several USB packets, so they are appended until
+/// the length in the TLV header (bytes 2..4) has been received.
+fn read_single_frame(transport: &mut dyn Transport) -> Result<Vec<u8>, Error> {
+    let mut response = Vec::new();
+    let mut packet = [0u8; MAX_CFG_LEN];
+    let mut length = None;
+    loop {
+        let len = transport.read_frame(&mut packet)?;
+        //println!("Read {} {}/{:?}", len, response.len(), length);
+        if len == 0 {
+            return Err(Error::Protocol(format!(
+                "Short read from the configuration interface, got {} bytes of {}.",
+                response.len(),
+                length.map_or("an unknown length".to_owned(), |l: usize| l.to_string())
+            )));
+        }
+        response.extend_from_slice(&packet[..len]);
+
+        if length.is_none() && response.len() >= 4 {
+            let l = usize::from(u16::from_le_bytes([response[2], response[3]]));
+            if l < 4 {
+                return Err(Error::Protocol(format!(
+                    "Invalid response length {}, the header alone is 4 bytes.",
+                    l
+                )));
+            }
+            if l > MAX_CFG_LEN {
+                return Err(Error::Protocol(format!("Overflow reading from the config interface, got {} bytes, max size is {} bytes.", l, MAX_CFG_LEN)));
+            }
+            length = Some(l);
+        }
+
+        if let Some(l) = length {
+            if response.len() > l {
+                return Err(Error::Protocol(format!(
+                    "Read {} bytes from the configuration interface, but the response is {} bytes.",
+                    response.len(),
+                    l
+                )));
+            }
+            if response.len() == l {
+                return Ok(response);
+            }
+        }
+    }
+}
+
+/// A transport which records every frame written to it and replays canned responses, used to
+/// exercise the commands without a device attached. Clones share the same state so a test can
+/// keep a handle after giving the transport to a `ConnectedDevice`.
+#[cfg(test)]
+#[derive(Debug, Default, Clone)]
+pub struct MemoryTransport(std::sync::Arc<parking_lot::Mutex<MemoryTransportState>>);
+
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryTransportState {
+    pub written: Vec<Vec<u8>>,
+    pub control_transfers: Vec<(u8, u8, u16, u16, Vec<u8>)>,
+    pub responses: std::collections::VecDeque<Vec<u8>>,
+    pub disconnected: bool,
+}
+
+#[cfg(test)]
+impl MemoryTransport {
+    pub fn with_responses(responses: Vec<Vec<u8>>) -> Self {
+        let transport = Self::default();
+        transport.state().responses = responses.into();
+        transport
+    }
+
+    pub fn state(&self) -> parking_lot::MutexGuard<'_, MemoryTransportState> {
+        self.0.lock()
+    }
+}
+
+#[cfg(test)]
+impl Transport for MemoryTransport {
+    fn write_frame(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut state = self.state();
+        if state.disconnected {
+            return Err(Error::usb(
+                "Failed to write to the configuration interface",
+                rusb::Error::NoDevice,
+            ));
+        }
+        state.written.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let response = self.state().responses.pop_front().ok_or_else(|| {
+            Error::usb(
+                "Error reading from the configuration inteface",
+                rusb::Error::Timeout,
+            )
+        })?;
+        let len = response.len().min(buf.len());
+        buf[..len].copy_from_slice(&response[..len]);
+        Ok(len)
+    }
+
+    fn write_control(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+    ) -> Result<usize, Error> {
+        self.state()
+            .control_transfers
+            .push((request_type, request, value, index, buf.to_vec()));
+        Ok(buf.len())
+    }
+
+    fn is_connected(&self) -> bool {
+        !self.state().disconnected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tlv::encode_tlv;
+
+    fn response() -> Vec<u8> {
+        let mut buf = vec![0, 0, 0, 0];
+        buf.extend((0..200).map(|x| x as u8));
+        let len = buf.len() as u16;
+        buf[2..4].copy_from_slice(&len.to_le_bytes());
+        buf
+    }
+
+    fn fragment(buf: &[u8], boundaries: &[usize]) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut start = 0;
+        for &end in boundaries.iter().chain(std::iter::once(&buf.len())) {
+            packets.push(buf[start..end].to_vec());
+            start = end;
+        }
+        packets
+    }
+
+    #[test]
+    fn single_packet() {
+        let mut transport = MemoryTransport::with_responses(vec![response()]);
+        assert_eq!(read_response(&mut transport).unwrap(), response());
+    }
+
+    #[test]
+    fn reassembles_at_any_boundary() {
+        let buf = response();
+        for split in 1..buf.len() {
+            let mut transport = MemoryTransport::with_responses(fragment(&buf, &[split]));
+            assert_eq!(
+                read_response(&mut transport).unwrap(),
+                buf,
+                "split at {}",
+                split
+            );
+        }
+    }
+
+    #[test]
+    fn reassembles_many_packets() {
+        let buf = response();
+        let boundaries: Vec<usize> = (1..buf.len()).step_by(3).collect();
+        let mut transport = MemoryTransport::with_responses(fragment(&buf, &boundaries));
+        assert_eq!(read_response(&mut transport).unwrap(), buf);
+
+        // Full speed bulk endpoints deliver 64 byte packets
+        let boundaries: Vec<usize> = (64..buf.len()).step_by(64).collect();
+        let mut transport = MemoryTransport::with_responses(fragment(&buf, &boundaries));
+        assert_eq!(read_response(&mut transport).unwrap(), buf);
+    }
+
+    #[test]
+    fn truncated_response_is_an_error() {
+        let buf = response();
+        let mut transport = MemoryTransport::with_responses(vec![buf[..100].to_vec()]);
+        assert!(read_response(&mut transport).is_err());
+
+        let mut transport = MemoryTransport::with_responses(vec![buf[..100].to_vec(), vec![]]);
+        let err = read_response(&mut transport).unwrap_err();
+        assert!(err.to_string().starts_with("Short read"), "{}", err);
+
+        let mut transport = MemoryTransport::with_responses(vec![vec![0, 0], vec![]]);
+        assert!(read_response(&mut transport).is_err());
+    }
+
+    fn chunks(buf: &[u8], data_len: usize) -> Vec<Vec<u8>> {
+        buf.chunks(data_len)
+            .enumerate()
+            .map(|(sequence, data)| {
+                let mut frame = Vec::new();
+                Chunk::new(sequence as u16, buf.len() as u32, data).write_as_binary(&mut frame);
+                frame
+            })
+            .collect()
+    }
+
+    fn large_response(len: usize) -> Vec<u8> {
+        let mut buf = vec![0, 0, 0, 0];
+        buf.extend((4..len).map(|x| x as u8));
+        buf[2..4].copy_from_slice(&(len as u16).to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn reassembles_chunks() {
+        let buf = large_response(1500);
+        let mut transport = MemoryTransport::with_responses(chunks(&buf, 500));
+        assert_eq!(read_response(&mut transport).unwrap(), buf);
+
+        // Chunks may themselves be split across packets
+        let packets = chunks(&buf, 500)
+            .iter()
+            .flat_map(|c| c.chunks(64).map(|p| p.to_vec()).collect::<Vec<_>>())
+            .collect();
+        let mut transport = MemoryTransport::with_responses(packets);
+        assert_eq!(read_response(&mut transport).unwrap(), buf);
+    }
+
+    #[test]
+    fn bad_chunks_are_errors() {
+        let buf = large_response(1500);
+        let mut frames = chunks(&buf, 500);
+        frames.swap(1, 2);
+        let mut transport = MemoryTransport::with_responses(frames);
+        assert!(read_response(&mut transport).is_err());
+
+        let mut frames = chunks(&buf, 500);
+        frames.pop();
+        let mut transport = MemoryTransport::with_responses(frames);
+        assert!(read_response(&mut transport).is_err());
+
+        let mut frames = chunks(&buf, 500);
+        frames[1] = response();
+        let mut transport = MemoryTransport::with_responses(frames);
+        assert!(read_response(&mut transport).is_err());
+
+        // Too short to hold a TLV header, or disagreeing with the header they carry
+        let mut empty = Vec::new();
+        Chunk::new(0, 0, &[]).write_as_binary(&mut empty);
+        for frames in [
+            vec![empty],
+            chunks(&[1, 0, 4], 500),
+            chunks(&large_response(1400)[..1000], 500),
+        ] {
+            let mut transport = MemoryTransport::with_responses(frames);
+            assert!(matches!(
+                read_response(&mut transport),
+                Err(Error::Protocol(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn writes_chunks() {
+        let buf = large_response(1500);
+        let mode = TransferMode::Chunked {
+            max_chunk_len: 512,
+            max_transfer_len: 4096,
+        };
+        let mut transport = MemoryTransport::default();
+        write_request(&mut transport, &buf, mode).unwrap();
+        assert_eq!(transport.state().written, chunks(&buf, 500));
+
+        // Small requests are still sent as a single frame
+        let mut transport = MemoryTransport::default();
+        write_request(&mut transport, &response(), mode).unwrap();
+        assert_eq!(transport.state().written, vec![response()]);
+    }
+
+    #[test]
+    fn large_requests_need_chunking() {
+        let buf = large_response(1500);
+        let mut transport = MemoryTransport::default();
+        assert!(write_request(&mut transport, &buf, TransferMode::SingleFrame).is_err());
+        assert!(transport.state().written.is_empty());
+
+        let mode = TransferMode::Chunked {
+            max_chunk_len: 512,
+            max_transfer_len: 1024,
+        };
+        assert!(write_request(&mut transport, &buf, mode).is_err());
+
+        // A TLV longer than its length field can describe is never sent
+        let mut buf = Vec::new();
+        encode_tlv(&mut buf, 0x4, |buf| buf.resize(buf.len() + 70000, 0));
+        let mode = TransferMode::Chunked {
+            max_chunk_len: 512,
+            max_transfer_len: MAX_CHUNKED_LEN,
+        };
+        assert!(write_request(&mut transport, &buf, mode).is_err());
+        assert!(transport.state().written.is_empty());
+    }
+
+    #[test]
+    fn transfer_mode_negotiation() {
+        let version = [0u8, 0, 12, 0, 0, 4, 8, 0, 4, 0, 4, 0];
+        assert_eq!(
+            TransferMode::from_version_response(&version),
+            TransferMode::SingleFrame
+        );
+
+        let mut chunked = version.to_vec();
+        chunked.extend_from_slice(&[1, 4, 12, 0, 0, 1, 0, 0, 0, 0, 1, 0]);
+        chunked[2] = chunked.len() as u8;
+        assert_eq!(
+            TransferMode::from_version_response(&chunked),
+            TransferMode::Chunked {
+                max_chunk_len: 256,
+                max_transfer_len: 65535
+            }
+        );
+    }
+
+    #[test]
+    fn bad_lengths_are_errors() {
+        let mut transport = MemoryTransport::with_responses(vec![vec![0, 0, 2, 0]]);
+        assert!(read_response(&mut transport).is_err());
+
+        let mut transport = MemoryTransport::with_responses(vec![vec![0, 0, 0xff, 0xff]]);
+        let err = read_response(&mut transport).unwrap_err();
+        assert!(err.to_string().starts_with("Overflow"), "{}", err);
+
+        let mut transport = MemoryTransport::with_responses(vec![vec![0, 0, 4, 0, 1]]);
+        assert!(read_response(&mut transport).is_err());
+    }
+
+    #[test]
+    fn responses_are_checked_for_a_nok() {
+        let ok = [StructureTypes::Ok as u8, 0, 4, 0];
+        let mut transport = MemoryTransport::with_responses(vec![ok.to_vec()]);
+        assert_eq!(read_ok_response(&mut transport).unwrap(), ok);
+
+        let nok = [StructureTypes::Nok as u8, 0, 4, 0];
+        let mut transport = MemoryTransport::with_responses(vec![nok.to_vec()]);
+        assert!(matches!(
+            read_ok_response(&mut transport),
+            Err(Error::Nok { .. })
+        ));
+
+        // A chunk promising an empty response used to reach the header checks with no header
+        let mut empty = Vec::new();
+        Chunk::new(0, 0, &[]).write_as_binary(&mut empty);
+        let mut transport = MemoryTransport::with_responses(vec![empty]);
+        assert!(matches!(
+            read_ok_response(&mut transport),
+            Err(Error::Protocol(_))
+        ));
+    }
+}
@@ -0,0 +1,155 @@
+use serde_json::Value;
+
+use crate::{capabilities::Capabilities, commands::StructureTypes, error::Error, Config};
+
+/// The largest relative difference allowed between a value we sent and the one read back. Gains
+/// are converted from dB to a linear factor and back, so they rarely survive exactly.
+const TOLERANCE: f64 = 1e-3;
+
+/// Compares the configuration that was sent to a device against the one it reports having
+/// applied, describing every field that differs. Disabled filters are never sent, so they are
+/// ignored, as are the codec settings of devices whose capabilities leave them out.
+pub fn compare(sent: &Config, applied: &Config, capabilities: &Capabilities) -> Vec<String> {
+    let mut sent = serde_json::to_value(sent).unwrap_or_default();
+    let applied = serde_json::to_value(applied).unwrap_or_default();
+    if let Some(filters) = sent.get_mut("filters").and_then(Value::as_array_mut) {
+        filters.retain(|f| f["enabled"] != Value::Bool(false));
+    }
+    if !capabilities.supports(StructureTypes::Pcm3060Configuration) {
+        if let Some(sent) = sent.as_object_mut() {
+            sent.remove("codec");
+        }
+    }
+
+    let mut mismatches = Vec::new();
+    compare_values("", &sent, &applied, &mut mismatches);
+    mismatches
+}
+
+/// Fails with the list of mismatches if the device didn't apply what we sent.
+pub fn check(sent: &Config, applied: &Config, capabilities: &Capabilities) -> Result<(), Error> {
+    let mismatches = compare(sent, applied, capabilities);
+    if !mismatches.is_empty() {
+        return Err(Error::Verification { mismatches });
+    }
+    Ok(())
+}
+
+fn compare_values(path: &str, sent: &Value, applied: &Value, mismatches: &mut Vec<String>) {
+    match (sent, applied) {
+        (Value::Number(a), Value::Number(b)) => {
+            let (a, b) = (
+                a.as_f64().unwrap_or(f64::NAN),
+                b.as_f64().unwrap_or(f64::NAN),
+            );
+            let close = (a - b).abs() <= TOLERANCE * a.abs().max(1.0);
+            if !close {
+                mismatches.push(format!("{}: sent {}, device has {}", path, a, b));
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            if a.len() != b.len() {
+                mismatches.push(format!(
+                    "{}: sent {} entries, device has {}",
+                    path,
+                    a.len(),
+                    b.len()
+                ));
+            }
+            for (i, (a, b)) in a.iter().zip(b).enumerate() {
+                compare_values(&format!("{}[{}]", path, i), a, b, mismatches);
+            }
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            for (key, a) in a {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                match b.get(key) {
+                    Some(b) => compare_values(&path, a, b, mismatches),
+                    None => mismatches.push(format!("{}: missing on the device", path)),
+                }
+            }
+        }
+        (a, b) if a != b => mismatches.push(format!("{}: sent {}, device has {}", path, a, b)),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filters::{CustomIIRFilter, LowpassFilter},
+        test_config, Codec, Preprocessing,
+    };
+
+    #[test]
+    fn identical_configs_match() {
+        let capabilities = Capabilities::default();
+        assert!(compare(
+            &test_config([(100.0, true)]),
+            &test_config([(100.0, true)]),
+            &capabilities
+        )
+        .is_empty());
+        // Disabled filters aren't expected on the device
+        assert!(compare(
+            &test_config([(100.0, true), (200.0, false)]),
+            &test_config([(100.0, true)]),
+            &capabilities
+        )
+        .is_empty());
+        // Rounding in the dB conversion is tolerated
+        let mut applied = test_config([]);
+        applied.preprocessing = Preprocessing::new(0.50001, 1.0, true);
+        assert!(compare(&test_config([]), &applied, &capabilities).is_empty());
+    }
+
+    #[test]
+    fn codec_settings_which_werent_sent_are_ignored() {
+        let mut applied = test_config([]);
+        applied.codec = Codec::new(true, true, true, true);
+        let mut capabilities = Capabilities::default();
+        assert!(!compare(&test_config([]), &applied, &capabilities).is_empty());
+        capabilities
+            .structures
+            .retain(|&s| s != StructureTypes::Pcm3060Configuration as u16);
+        assert!(compare(&test_config([]), &applied, &capabilities).is_empty());
+    }
+
+    #[test]
+    fn mismatches_are_reported() {
+        let capabilities = Capabilities::default();
+        let sent = test_config([(100.0, true), (200.0, true), (300.0, true)]);
+        let mut applied = test_config([(100.0, true), (250.0, true)]);
+        applied.codec = Codec::new(true, true, true, false);
+        assert_eq!(
+            compare(&sent, &applied, &capabilities),
+            vec![
+                "codec.phase: sent false, device has true",
+                "filters: sent 3 entries, device has 2",
+                "filters[1].f0: sent 200, device has 250",
+            ]
+        );
+
+        // A different kind of filter in the same slot
+        let mut applied = test_config([]);
+        applied
+            .filters
+            .add(LowpassFilter::new(100.0, 0.7).unwrap().into(), true);
+        assert!(!compare(&test_config([(100.0, true)]), &applied, &capabilities).is_empty());
+
+        let mut applied = test_config([]);
+        applied.filters.add(
+            CustomIIRFilter::new(1.0, 0.0, 0.0, 1.0, 0.0, 0.0).into(),
+            true,
+        );
+        match check(&test_config([(100.0, true)]), &applied, &capabilities) {
+            Err(Error::Verification { mismatches }) => assert!(!mismatches.is_empty()),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,564 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use parking_lot::Mutex;
+
+use crate::{error::Error, Config, ConnectionState, WriteOutcome};
+
+/// How long most commands may wait in the queue and run before the caller gives up.
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+/// Saving and factory resets erase flash, which takes a good deal longer.
+pub const FLASH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A job which can't run yet hands back another one to run once `Deferred::delay` has passed.
+type Job = Box<dyn FnOnce(&mut ConnectionState) -> Option<Deferred> + Send>;
+
+struct Deferred {
+    delay: Duration,
+    job: Job,
+}
+
+struct Request {
+    name: &'static str,
+    job: Job,
+    cancelled: Arc<AtomicBool>,
+    generation: u64,
+    /// Run deferred jobs early before this one, so it sees the device as the caller left it.
+    flush: bool,
+}
+
+type WriteResult = mpsc::SyncSender<Result<WriteOutcome, Error>>;
+type LatestWrites = Arc<Mutex<HashMap<String, PendingWrites>>>; // Keyed by serial number
+
+/// The configuration writes to one device which haven't reached it yet. Only the latest of them
+/// is sent, and its outcome is reported to the callers of every write it replaced.
+#[derive(Default)]
+struct PendingWrites {
+    latest: u64,
+    waiters: Vec<WriteResult>,
+}
+
+/// Carried by a queued write. If the write is dropped without running while it is the latest, the
+/// callers waiting on it are told it was cancelled.
+struct WriteTicket {
+    writes: LatestWrites,
+    key: String,
+    id: u64,
+}
+
+impl WriteTicket {
+    /// Takes the callers waiting for this write, or None if a newer write has replaced it and will
+    /// answer them.
+    fn take_waiters(&self) -> Option<Vec<WriteResult>> {
+        match self.writes.lock().get_mut(&self.key) {
+            Some(pending) if pending.latest == self.id => {
+                Some(std::mem::take(&mut pending.waiters))
+            }
+            _ => None,
+        }
+    }
+
+    fn is_superseded(&self) -> bool {
+        self.writes
+            .lock()
+            .get(&self.key)
+            .is_some_and(|pending| pending.latest != self.id)
+    }
+}
+
+impl Drop for WriteTicket {
+    fn drop(&mut self) {
+        // Dropping the senders wakes the callers with a cancellation
+        self.take_waiters();
+    }
+}
+
+/// A queued command, its result is collected with `wait`.
+pub struct Pending<R> {
+    name: &'static str,
+    result: mpsc::Receiver<Result<R, Error>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl<R> Pending<R> {
+    /// Waits up to `timeout` for the result, cancelling the command if it hasn't started by then.
+    pub fn wait(self, timeout: Duration) -> Result<R, Error> {
+        match self.result.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                warn!("{} timed out after {:?}", self.name, timeout);
+                self.cancelled.store(true, Ordering::Release);
+                Err(Error::Timeout(self.name.to_owned()))
+            }
+            // The job was dropped without running
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Err(Error::Cancelled(self.name.to_owned()))
+            }
+        }
+    }
+}
+
+/// Owns the connection on a dedicated thread and runs commands against it one at a time, so
+/// concurrent invokes can't interleave their frames on the bus. Commands which time out, or are
+/// cancelled with `cancel_pending`, are skipped if they haven't started yet. A command which has
+/// started always runs to completion, each transfer is bounded by `USB_TIMEOUT`. Writes held back
+/// by `write_interval` wait without blocking the thread, and are sent early if another command
+/// arrives in the meantime.
+#[derive(Clone)]
+pub struct UsbWorker {
+    sender: mpsc::Sender<Request>,
+    generation: Arc<AtomicU64>,
+    latest_writes: LatestWrites,
+}
+
+impl UsbWorker {
+    pub fn spawn(mut connection: ConnectionState) -> Self {
+        let (sender, requests) = mpsc::channel::<Request>();
+        let generation = Arc::new(AtomicU64::new(0));
+        let current = generation.clone();
+        thread::Builder::new()
+            .name("usb-worker".to_owned())
+            .spawn(move || {
+                let mut deferred: Vec<(Instant, Request)> = Vec::new();
+                loop {
+                    let received = match deferred.iter().map(|(due, _)| *due).min() {
+                        Some(due) => {
+                            requests.recv_timeout(due.saturating_duration_since(Instant::now()))
+                        }
+                        None => requests
+                            .recv()
+                            .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                    };
+                    let ready = match received {
+                        Ok(request) => {
+                            let mut ready = Vec::new();
+                            if request.flush {
+                                ready.extend(deferred.drain(..).map(|(_, r)| r));
+                            }
+                            ready.push(request);
+                            ready
+                        }
+                        Err(mpsc::RecvTimeoutError::Timeout) => {
+                            let now = Instant::now();
+                            let (due, later) = deferred.drain(..).partition(|(d, _)| *d <= now);
+                            deferred = later;
+                            due.into_iter().map(|(_, r)| r).collect()
+                        }
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    };
+                    for request in ready {
+                        if let Some(later) = run(&mut connection, request, &current) {
+                            deferred.push(later);
+                        }
+                    }
+                }
+            })
+            .expect("Failed to start the USB worker");
+        Self {
+            sender,
+            generation,
+            latest_writes: Arc::default(),
+        }
+    }
+
+    /// Queues `f` and waits up to `timeout` for its result.
+    pub fn call<R: Send + 'static>(
+        &self,
+        name: &'static str,
+        timeout: Duration,
+        f: impl FnOnce(&mut ConnectionState) -> Result<R, Error> + Send + 'static,
+    ) -> Result<R, Error> {
+        self.queue(name, f)?.wait(timeout)
+    }
+
+    /// Queues `f` without waiting for it, the result is collected from the returned `Pending`.
+    pub fn queue<R: Send + 'static>(
+        &self,
+        name: &'static str,
+        f: impl FnOnce(&mut ConnectionState) -> Result<R, Error> + Send + 'static,
+    ) -> Result<Pending<R>, Error> {
+        let (result_sender, result) = mpsc::sync_channel(1);
+        let cancelled = self.send(
+            name,
+            Box::new(move |connection| {
+                let _ = result_sender.send(f(connection));
+                None
+            }),
+            true,
+        )?;
+        Ok(Pending {
+            name,
+            result,
+            cancelled,
+        })
+    }
+
+    fn send(&self, name: &'static str, job: Job, flush: bool) -> Result<Arc<AtomicBool>, Error> {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let request = Request {
+            name,
+            job,
+            cancelled: cancelled.clone(),
+            generation: self.generation.load(Ordering::Acquire),
+            flush,
+        };
+        self.sender
+            .send(request)
+            .map_err(|_| Error::Cancelled(name.to_owned()))?;
+        Ok(cancelled)
+    }
+
+    /// Like `call`, but waits on a blocking thread so async commands don't hold up the runtime.
+    pub async fn run<R: Send + 'static>(
+        &self,
+        name: &'static str,
+        timeout: Duration,
+        f: impl FnOnce(&mut ConnectionState) -> Result<R, Error> + Send + 'static,
+    ) -> Result<R, Error> {
+        let worker = self.clone();
+        tauri::async_runtime::spawn_blocking(move || worker.call(name, timeout, f))
+            .await
+            .map_err(|_| Error::Cancelled(name.to_owned()))?
+    }
+
+    /// Like `run`, for a command addressed to a particular device, or the current one.
+    pub async fn run_on<R: Send + 'static>(
+        &self,
+        name: &'static str,
+        timeout: Duration,
+        serial_number: Option<String>,
+        f: impl FnOnce(&mut ConnectionState) -> Result<R, Error> + Send + 'static,
+    ) -> Result<R, Error> {
+        self.run(name, timeout, move |c| {
+            c.with_device(serial_number.as_deref(), f)
+        })
+        .await
+    }
+
+    /// Writes a configuration, coalescing rapid writes to the same device. A write which is
+    /// superseded by a newer one before it reaches the device is dropped, and its caller gets the
+    /// outcome of the newer write. Writes are held back until `write_interval` has passed since
+    /// the last one. The device must be named, which device is current may change while the
+    /// write is queued.
+    pub async fn write_config(
+        &self,
+        serial_number: String,
+        config: Config,
+    ) -> Result<WriteOutcome, Error> {
+        let pending = self.queue_write(serial_number, config)?;
+        tauri::async_runtime::spawn_blocking(move || pending.wait(COMMAND_TIMEOUT))
+            .await
+            .map_err(|_| Error::Cancelled("write_config".to_owned()))?
+    }
+
+    fn queue_write(
+        &self,
+        serial_number: String,
+        config: Config,
+    ) -> Result<Pending<WriteOutcome>, Error> {
+        let (result_sender, result) = mpsc::sync_channel(1);
+        let id = {
+            let mut latest_writes = self.latest_writes.lock();
+            let pending = latest_writes.entry(serial_number.clone()).or_default();
+            pending.latest += 1;
+            pending.waiters.push(result_sender);
+            pending.latest
+        };
+        let ticket = WriteTicket {
+            writes: self.latest_writes.clone(),
+            key: serial_number.clone(),
+            id,
+        };
+        let job: Job = Box::new(move |c| {
+            // The callers stay with the pending writes until it's sent, a newer write may take
+            // them over while this one waits
+            if ticket.is_superseded() {
+                return None;
+            }
+            let delay = c.write_delay(&serial_number);
+            let send: Job = Box::new(move |c| {
+                let waiters = ticket.take_waiters()?;
+                let result = c.with_device(Some(&serial_number), |c| c.write_config(&config));
+                for waiter in waiters {
+                    let _ = waiter.send(result.clone());
+                }
+                None
+            });
+            if delay.is_zero() {
+                return send(c);
+            }
+            Some(Deferred { delay, job: send })
+        });
+        let cancelled = self.send("write_config", job, false)?;
+        Ok(Pending {
+            name: "write_config",
+            result,
+            cancelled,
+        })
+    }
+
+    /// Drops every command which is queued but hasn't started.
+    pub fn cancel_pending(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+/// Runs a request unless it was cancelled, returning the request to run later if it was deferred.
+fn run(
+    connection: &mut ConnectionState,
+    request: Request,
+    current: &AtomicU64,
+) -> Option<(Instant, Request)> {
+    if request.cancelled.load(Ordering::Acquire)
+        || request.generation < current.load(Ordering::Acquire)
+    {
+        info!("Skipping cancelled command {}", request.name);
+        return None;
+    }
+    let deferred = (request.job)(connection)?;
+    let due = Instant::now() + deferred.delay;
+    Some((
+        due,
+        Request {
+            job: deferred.job,
+            ..request
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_config, transport::MemoryTransport, ConnectedDevice};
+    use std::time::Instant;
+
+    fn connected(transport: &MemoryTransport) -> UsbWorker {
+        UsbWorker::spawn(ConnectionState::connected_to(transport.clone()))
+    }
+
+    /// Queues a command which blocks the worker until the returned sender is dropped.
+    fn block(worker: &UsbWorker) -> mpsc::Sender<()> {
+        let (release, released) = mpsc::channel::<()>();
+        let (started, wait_started) = mpsc::channel();
+        let worker = worker.clone();
+        thread::spawn(move || {
+            worker.call("block", Duration::from_secs(5), move |_| {
+                let _ = started.send(());
+                let _ = released.recv();
+                Ok(())
+            })
+        });
+        wait_started.recv().unwrap();
+        release
+    }
+
+    #[test]
+    fn commands_run_in_order() {
+        let worker = UsbWorker::spawn(ConnectionState::default());
+        worker
+            .call("first", COMMAND_TIMEOUT, |c| {
+                c.verify_writes = true;
+                Ok(())
+            })
+            .unwrap();
+        assert!(worker
+            .call("second", COMMAND_TIMEOUT, |c| Ok(c.verify_writes))
+            .unwrap());
+        assert!(matches!(
+            worker.call("failing", COMMAND_TIMEOUT, |c| c.capabilities()),
+            Err(Error::NotConnected)
+        ));
+    }
+
+    #[test]
+    fn queued_commands_time_out() {
+        let worker = UsbWorker::spawn(ConnectionState::default());
+        let release = block(&worker);
+        let result = worker.call("write_config", Duration::from_millis(10), |c| {
+            c.verify_writes = true;
+            Ok(())
+        });
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "write_config: Operation timed out"
+        );
+
+        // The timed out command never runs
+        drop(release);
+        assert!(!worker
+            .call("check", COMMAND_TIMEOUT, |c| Ok(c.verify_writes))
+            .unwrap());
+    }
+
+    #[test]
+    fn pending_commands_can_be_cancelled() {
+        let worker = UsbWorker::spawn(ConnectionState::default());
+        let release = block(&worker);
+        let queued = worker.queue("queued", |_| Ok(())).unwrap();
+        worker.cancel_pending();
+        drop(release);
+        assert!(matches!(
+            queued.wait(COMMAND_TIMEOUT),
+            Err(Error::Cancelled(_))
+        ));
+
+        // Commands queued after the cancellation still run
+        assert!(worker.call("after", COMMAND_TIMEOUT, |_| Ok(())).is_ok());
+    }
+
+    #[test]
+    fn superseded_writes_are_dropped() {
+        let transport = MemoryTransport::with_responses(vec![vec![0, 0, 4, 0]; 3]);
+        let worker = connected(&transport);
+        let release = block(&worker);
+        let writes: Vec<_> = [100.0, 200.0, 300.0]
+            .into_iter()
+            .map(|f0| {
+                worker
+                    .queue_write("test".to_owned(), test_config([(f0, true)]))
+                    .unwrap()
+            })
+            .collect();
+        drop(release);
+        for write in writes {
+            assert!(write.wait(COMMAND_TIMEOUT).is_ok());
+        }
+        // Only the latest configuration reached the device
+        assert_eq!(transport.state().written.len(), 1);
+    }
+
+    #[test]
+    fn superseded_writes_share_the_outcome() {
+        // The device never answers, so the write which is sent fails
+        let transport = MemoryTransport::default();
+        let worker = connected(&transport);
+        let release = block(&worker);
+        let writes: Vec<_> = [100.0, 200.0]
+            .into_iter()
+            .map(|f0| {
+                worker
+                    .queue_write("test".to_owned(), test_config([(f0, true)]))
+                    .unwrap()
+            })
+            .collect();
+        drop(release);
+        for write in writes {
+            assert!(matches!(
+                write.wait(COMMAND_TIMEOUT),
+                Err(Error::Timeout(_))
+            ));
+        }
+        assert_eq!(transport.state().written.len(), 1);
+
+        // Nothing is sent if the latest write is cancelled, and nobody is told it succeeded
+        let release = block(&worker);
+        let writes: Vec<_> = [100.0, 200.0]
+            .into_iter()
+            .map(|f0| {
+                worker
+                    .queue_write("test".to_owned(), test_config([(f0, true)]))
+                    .unwrap()
+            })
+            .collect();
+        worker.cancel_pending();
+        drop(release);
+        for write in writes {
+            assert!(matches!(
+                write.wait(COMMAND_TIMEOUT),
+                Err(Error::Cancelled(_))
+            ));
+        }
+        assert_eq!(transport.state().written.len(), 1);
+    }
+
+    #[test]
+    fn writes_respect_the_interval() {
+        let transport = MemoryTransport::with_responses(vec![vec![0, 0, 4, 0]; 2]);
+        let worker = connected(&transport);
+        worker
+            .call("set_write_interval", COMMAND_TIMEOUT, |c| {
+                c.write_interval = Duration::from_millis(100);
+                Ok(())
+            })
+            .unwrap();
+
+        let start = Instant::now();
+        tauri::async_runtime::block_on(
+            worker.write_config("test".to_owned(), test_config([(100.0, true)])),
+        )
+        .unwrap();
+        tauri::async_runtime::block_on(
+            worker.write_config("test".to_owned(), test_config([(200.0, true)])),
+        )
+        .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(transport.state().written.len(), 2);
+    }
+
+    #[test]
+    fn held_back_writes_dont_block_other_commands() {
+        let transport = MemoryTransport::with_responses(vec![vec![0, 0, 4, 0]; 2]);
+        let worker = connected(&transport);
+        worker
+            .call("set_write_interval", COMMAND_TIMEOUT, |c| {
+                c.write_interval = Duration::from_secs(1);
+                Ok(())
+            })
+            .unwrap();
+        worker
+            .queue_write("test".to_owned(), test_config([(100.0, true)]))
+            .unwrap()
+            .wait(COMMAND_TIMEOUT)
+            .unwrap();
+
+        let start = Instant::now();
+        let held = worker
+            .queue_write("test".to_owned(), test_config([(200.0, true)]))
+            .unwrap();
+        // The held back write is sent first, so the command sees the device up to date
+        let state = transport.clone();
+        let written = worker
+            .call("check", COMMAND_TIMEOUT, move |_| {
+                Ok(state.state().written.len())
+            })
+            .unwrap();
+        assert_eq!(written, 2);
+        held.wait(COMMAND_TIMEOUT).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn writes_to_other_devices_are_not_superseded() {
+        let first = MemoryTransport::with_responses(vec![vec![0, 0, 4, 0]]);
+        let second = MemoryTransport::with_responses(vec![vec![0, 0, 4, 0]]);
+        let mut connection = ConnectionState::connected_to(first.clone());
+        connection
+            .devices
+            .insert("second".to_owned(), ConnectedDevice::new(second.clone()));
+        let worker = UsbWorker::spawn(connection);
+
+        let release = block(&worker);
+        let writes: Vec<_> = ["test", "second"]
+            .into_iter()
+            .map(|serial_number| {
+                worker
+                    .queue_write(serial_number.to_owned(), test_config([(100.0, true)]))
+                    .unwrap()
+            })
+            .collect();
+        drop(release);
+        for write in writes {
+            write.wait(COMMAND_TIMEOUT).unwrap();
+        }
+        assert_eq!(first.state().written.len(), 1);
+        assert_eq!(second.state().written.len(), 1);
+    }
+}